@@ -1,17 +1,22 @@
-use std::{collections::HashSet, process::Command};
+use std::{collections::BTreeMap, path::Path};
 
+use anyhow::Context;
 use async_trait::async_trait;
 use clap::{Parser, Subcommand};
-use regex::Regex;
+use colored::*;
 
 use crate::{
+    errors::{self, LsctlError},
     models::fly_models::DeployConfig,
-    utils::{collection_utils, command_utils},
+    utils::fly_client::{FlyClient, FlyDeployArgs, Flyctl},
 };
 
-use super::{FlyConfigGenOptions, FlyConfigSubcommand};
-
-static FLYCTL: &str = "flyctl";
+use super::{
+    failed_after_deploy, failed_step, insert_environment_variables, latest_stable_release,
+    preview_config_file_options, rollback, CommandRunner, ConfigFileOptions, FlyAppState,
+    FlyConfigGenOptions, FlyConfigSubcommand, FlyDeployPlan, FlyDeployState, FlyDiff,
+    FlyPreviewSubcommand, FlyRollback, SecretOptions, DEPLOY_STATE_DIR, STEP_NAMES,
+};
 
 #[derive(Clone, Parser, Debug)]
 pub struct FlyDeploy {
@@ -38,275 +43,227 @@ pub struct FlyDeploy {
     /// Return immediately instead of monitoring deployment progress
     #[clap(long)]
     pub detach: bool,
-}
 
-#[async_trait]
-impl super::CommandRunner for FlyDeploy {
-    async fn execute(&self) -> anyhow::Result<()> {
-        let fly_config_gen = FlyConfigGenOptions {
-            output_file: "fly.toml".to_string(),
-            input_files: self.input_files.clone(),
-        };
+    /// Print the flyctl commands that would be run and exit without changing anything
+    #[clap(long)]
+    pub plan: bool,
 
-        fly_config_gen.execute().await.unwrap();
+    /// Deploy a preview app for this git branch instead of the app in the config
+    #[clap(long, value_name = "BRANCH")]
+    pub preview: Option<String>,
 
-        let deploy_config = DeployConfig::new(&self.input_files)?;
+    /// Create and attach a new Postgres database for the preview app
+    #[clap(long, requires = "preview")]
+    pub preview_database: bool,
 
-        let deploy_config_hooks = deploy_config.hooks;
+    /// Roll back to the release that was running before when the deploy or a step after it fails
+    #[clap(long)]
+    pub rollback_on_failure: bool,
 
-        let fly_apps_stdout = command_utils::stdout_or_bail2(
-            Command::new(FLYCTL).arg("apps").arg("list"),
-            "Failed to get Fly apps",
-        )
-        .unwrap();
+    /// How long to wait for the http_checks to pass on the public hostname, in seconds
+    #[clap(long, default_value = "300")]
+    pub health_check_timeout: u64,
 
-        let should_launch = !Regex::new(&format!("{}\\s+", deploy_config.name))
-            .unwrap()
-            .is_match(&fly_apps_stdout);
-
-        let fly_app_secrets = if !should_launch {
-            command_utils::stdout_or_bail2(
-                Command::new(FLYCTL)
-                    .arg("secrets")
-                    .arg("list")
-                    .arg("--app")
-                    .arg(deploy_config.name.as_str()),
-                "Failed to get Fly app secrets",
-            )
-            .unwrap()
-            .split("\n")
-            .filter_map(|line| {
-                let line = line.trim();
-                let parts = line.split(" ").collect::<Vec<_>>();
-
-                match parts.get(0) {
-                    Some(&secret) => Some(secret.to_string()),
-                    None => None,
-                }
-            })
-            .collect::<Vec<_>>()
-        } else {
-            vec![]
-        };
+    /// Do not wait for the http_checks to pass after deploying
+    #[clap(long)]
+    pub skip_health_checks: bool,
 
-        if should_launch {
-            println!("Launching new app");
-
-            command_utils::stream_stdout_or_bail(
-                Command::new(FLYCTL)
-                    .arg("launch")
-                    .arg("--no-deploy")
-                    .arg("--copy-config")
-                    .arg("--name")
-                    .arg(deploy_config.name.as_str())
-                    .arg("--org")
-                    .arg(deploy_config.organization.as_str())
-                    .arg("--region")
-                    .arg(deploy_config.default_region.as_str()),
-                "Failed to start Fly app",
-            )
-            .unwrap();
-        }
+    /// Unset the secrets on Fly that the config does not declare
+    #[clap(long)]
+    pub prune_secrets: bool,
 
-        if let Some(database) = deploy_config.database {
-            if let Some(postgres) = database.postgres {
-                let postgres_name = format!("{}-postgres", deploy_config.name);
-                let should_attach_postgres = !fly_app_secrets.contains(&"DATABASE_URL".to_string());
-                let should_create_postgres = should_attach_postgres
-                    && !Regex::new(&format!("{}\\s+", postgres_name))
-                        .unwrap()
-                        .is_match(&fly_apps_stdout);
-
-                if should_create_postgres {
-                    println!("Creating new Postgres database");
-
-                    command_utils::stream_stdout_or_bail(
-                        Command::new(FLYCTL)
-                            .arg("postgres")
-                            .arg("create")
-                            .arg("--name")
-                            .arg(&postgres_name)
-                            .arg("--organization")
-                            .arg(deploy_config.organization.as_str())
-                            .arg("--region")
-                            .arg(deploy_config.default_region.as_str())
-                            .arg("--volume-size")
-                            .arg(postgres.volume_size.to_string())
-                            .arg("--initial-cluster-size")
-                            .arg("2")
-                            .arg("--vm-size")
-                            .arg(postgres.vm_size.to_string()),
-                        "Failed to create Fly app database",
-                    )
-                    .unwrap();
-                }
+    /// Write decrypted values into fly.toml even if it is tracked by git
+    #[clap(long)]
+    pub allow_tracked: bool,
 
-                if should_attach_postgres {
-                    println!("Attaching the Postgres database");
-
-                    command_utils::stream_stdout_or_bail(
-                        Command::new(FLYCTL)
-                            .arg("postgres")
-                            .arg("attach")
-                            .arg("--postgres-app")
-                            .arg(&postgres_name)
-                            .arg("--app")
-                            .arg(&deploy_config.name),
-                        "Failed to attach Fly app database",
-                    )
-                    .unwrap();
-                }
-            }
+    /// Skip the steps that completed in the last deploy of the app, when it did not finish. The
+    /// config and image must not have changed since
+    #[clap(long)]
+    pub resume: bool,
+
+    /// Only run these steps, e.g. `--only autoscale,scale-memory`. The steps that completed in
+    /// the last deploy are not updated
+    #[clap(
+        long,
+        value_name = "STEP",
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        possible_values = STEP_NAMES,
+        conflicts_with = "skip"
+    )]
+    pub only: Vec<String>,
+
+    /// Do not run these steps. The steps that completed in the last deploy are not updated
+    #[clap(
+        long,
+        value_name = "STEP",
+        multiple_occurrences = true,
+        use_value_delimiter = true,
+        possible_values = STEP_NAMES
+    )]
+    pub skip: Vec<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+
+    #[clap(flatten)]
+    pub secret_options: SecretOptions,
+}
+
+impl FlyDeploy {
+    /// The options of the `fly config gen` that writes the fly.toml to deploy.
+    fn config_gen_options(&self, config_file_options: ConfigFileOptions) -> FlyConfigGenOptions {
+        FlyConfigGenOptions {
+            output_file: "fly.toml".to_string(),
+            input_files: self.input_files.clone(),
+            redact: false,
+            merged_file: None,
+            allow_tracked: self.allow_tracked,
+            config_file_options,
+            secret_options: self.secret_options.clone(),
         }
+    }
 
-        if let Some(hooks) = deploy_config_hooks.clone() {
-            if let Some(pre_deploy) = hooks.pre_deploy {
-                println!("Running pre-deploy hook");
+    fn deploy_args(&self) -> FlyDeployArgs {
+        FlyDeployArgs {
+            image: self.image.clone(),
+            local_only: self.local_only,
+            remote_only: self.remote_only,
+            no_cache: self.no_cache,
+            detach: self.detach,
+        }
+    }
 
-                let pre_deploy_vec = pre_deploy.split(" ").collect::<Vec<_>>();
-                let (program, args) = pre_deploy_vec.split_at(1);
+    async fn deploy<C: FlyClient>(&self, client: &C) -> anyhow::Result<()> {
+        let config_file_options = match &self.preview {
+            Some(branch) => preview_config_file_options(
+                &self.config_file_options,
+                &self.input_files,
+                branch,
+                self.preview_database,
+            )?,
+            None => self.config_file_options.clone(),
+        };
+        let sources = config_file_options.sources(&self.input_files)?;
+        let deploy_config = DeployConfig::from_sources(&sources)?;
+        let app_state =
+            FlyAppState::fetch(client, &deploy_config).map_err(|e| LsctlError::Flyctl {
+                step: "fetch-app-state".to_string(),
+                exit_code: errors::command_exit_code(&e),
+                source: e,
+            })?;
+        let mut plan = FlyDeployPlan::new(&deploy_config, &app_state, &self.deploy_args());
+
+        plan.set_health_check_timeout(if self.skip_health_checks {
+            None
+        } else {
+            Some(self.health_check_timeout)
+        });
+        plan.set_allow_missing_secrets(self.secret_options.allow_missing_secrets);
 
-                command_utils::stream_stdout_or_bail(
-                    Command::new(program[0]).args(args),
-                    "Failed to run post-deploy hook",
-                )
-                .unwrap();
-            }
+        if self.prune_secrets {
+            plan.prune_secrets(&deploy_config, &app_state);
         }
 
-        let mut args = vec!["deploy", "--region", deploy_config.default_region.as_str()];
+        plan.select(&self.only, &self.skip);
 
-        if let Some(image) = &self.image {
-            args.push("--image");
-            args.push(image.as_str());
-        }
+        let mut state = FlyDeployState::open(
+            Path::new(DEPLOY_STATE_DIR),
+            &deploy_config.name,
+            &FlyDeployState::fingerprint(&sources.rendered()?, &self.deploy_args()),
+            self.resume,
+        )?;
 
-        if self.local_only {
-            args.push("--local-only");
+        if !self.only.is_empty() || !self.skip.is_empty() {
+            state.detach();
         }
 
-        if self.remote_only {
-            args.push("--remote-only");
+        for step in state.skip_completed(&mut plan) {
+            println!(
+                "{}",
+                format!("Skipping {}, it completed in the last deploy", step.name()).yellow()
+            );
         }
 
-        if self.no_cache {
-            args.push("--no-cache");
-        }
+        if self.plan {
+            print!("{}", plan);
 
-        if self.detach {
-            args.push("--detach");
+            return anyhow::Ok(());
         }
 
-        println!("Deploying the app");
+        self.config_gen_options(config_file_options)
+            .execute()
+            .await?;
 
-        command_utils::stream_stdout_or_bail(
-            Command::new(FLYCTL).args(args),
-            "Failed to deploy the app",
+        let secret_values = insert_environment_variables(
+            &deploy_config,
+            &deploy_config.secret_environment(),
+            &self.secret_options,
         )
-        .unwrap();
-
-        if deploy_config.scaling.balance_method.is_static() {
-            println!(
-                "Updating app scaling to {}",
-                deploy_config.scaling.min_count
-            );
+        .await?
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
 
-            command_utils::stream_stdout_or_bail(
-                Command::new(FLYCTL)
-                    .arg("scale")
-                    .arg("count")
-                    .arg(deploy_config.scaling.min_count.to_string())
-                    .arg("--app")
-                    .arg(&deploy_config.name),
-                "Failed to set scaling on the app",
-            )
-            .unwrap();
+        let previous_release = if self.rollback_on_failure && app_state.app_exists {
+            latest_stable_release(&client.releases(&deploy_config.name)?)
         } else {
-            println!(
-                "Updating app autoscaling to method: {}, min: {}, max: {}",
-                deploy_config.scaling.balance_method.to_string(),
-                deploy_config.scaling.min_count,
-                deploy_config.scaling.max_count,
-            );
-
-            command_utils::stream_stdout_or_bail(
-                Command::new(FLYCTL)
-                    .arg("autoscale")
-                    .arg(deploy_config.scaling.balance_method.to_string())
-                    .arg("--app")
-                    .arg(&deploy_config.name)
-                    .arg(&format!("min={}", deploy_config.scaling.min_count))
-                    .arg(&format!("max={}", deploy_config.scaling.max_count)),
-                "Failed to set autoscaling on the app",
-            )
-            .unwrap();
-        }
+            None
+        };
 
-        println!("Updating app memory to {}mb", deploy_config.scaling.memory);
-        command_utils::stream_stdout_or_bail(
-            Command::new(FLYCTL)
-                .arg("scale")
-                .arg("memory")
-                .arg(deploy_config.scaling.memory.to_string())
-                .arg("--app")
-                .arg(&deploy_config.name),
-            "Failed to set memory on the app",
-        )
-        .unwrap();
+        state.save()?;
 
-        let mut regions: HashSet<String> = HashSet::from_iter(deploy_config.regions);
-        regions.insert(deploy_config.default_region);
+        let result =
+            plan.apply_with_progress(client, &secret_values, &mut |step| state.complete(step));
+        let error = match result {
+            Ok(_) => return state.remove(),
+            Err(e) => e,
+        };
 
-        println!(
-            "Updating app regions to {}",
-            collection_utils::join_hash_set_of_strings(&regions, ", ")
+        let on_failure = FlyDeployPlan::on_failure(
+            &deploy_config,
+            &self.deploy_args(),
+            failed_step(&error).as_deref(),
         );
 
-        command_utils::stdout_or_bail2(
-            Command::new(FLYCTL)
-                .arg("regions")
-                .arg("set")
-                .args(regions)
-                .arg("--app")
-                .arg(&deploy_config.name),
-            "Failed to set regions on the app",
-        )
-        .unwrap();
-
-        let regions: HashSet<String> = HashSet::from_iter(deploy_config.backup_regions);
-
-        println!(
-            "Updating app backup regions {}",
-            collection_utils::join_hash_set_of_strings(&regions, ", ")
-        );
+        if let Err(e) = on_failure.apply(client, &secret_values) {
+            println!("{}", format!("{}, continuing", e).yellow());
+        }
 
-        command_utils::stream_stdout_or_bail(
-            Command::new(FLYCTL)
-                .arg("regions")
-                .arg("backup")
-                .args(regions)
-                .arg("--app")
-                .arg(&deploy_config.name),
-            "Failed to set backup regions on the app",
-        )
-        .unwrap();
+        match previous_release {
+            Some(release) if failed_after_deploy(&plan, &error) => {
+                println!("{}", "The deploy failed, rolling back".yellow());
 
-        if let Some(hooks) = deploy_config_hooks.clone() {
-            if let Some(post_deploy) = hooks.post_deploy {
-                println!("Running post-deploy hook");
+                match rollback(client, &deploy_config, &release) {
+                    Ok(_) => {
+                        state.remove()?;
 
-                let post_deploy_vec = post_deploy.split(" ").collect::<Vec<_>>();
-                let (program, args) = post_deploy_vec.split_at(1);
+                        Err(error.context(format!("Rolled back to v{}", release.version)))
+                    }
+                    Err(e) => Err(error).with_context(|| format!("The rollback failed: {:?}", e)),
+                }
+            }
+            _ => {
+                if state.is_saved() && !state.completed_steps.is_empty() {
+                    println!(
+                        "{}",
+                        format!(
+                            "Run the deploy again with --resume to skip the {} completed step(s)",
+                            state.completed_steps.len()
+                        )
+                        .yellow()
+                    );
+                }
 
-                command_utils::stream_stdout_or_bail(
-                    Command::new(program[0]).args(args),
-                    "Failed to run post-deploy hook",
-                )
-                .unwrap();
+                Err(error)
             }
         }
+    }
+}
 
-        anyhow::Ok(())
+#[async_trait]
+impl CommandRunner for FlyDeploy {
+    async fn execute(&self) -> anyhow::Result<()> {
+        self.deploy(&Flyctl).await
     }
 }
 
@@ -317,4 +274,71 @@ pub enum FlySubcommand {
     Config(FlyConfigSubcommand),
     /// Deploys a app to fly
     Deploy(FlyDeploy),
+    /// Shows where the app on Fly differs from the config
+    Diff(FlyDiff),
+    /// Redeploys the image of an earlier release
+    Rollback(FlyRollback),
+    /// Used for managing preview apps of git branches
+    #[clap(subcommand)]
+    Preview(FlyPreviewSubcommand),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{models::flyctl_models::FlyctlApp, utils::fly_client::RecordingFlyClient};
+
+    #[tokio::test]
+    async fn plan_returns_before_generating_or_changing_anything() {
+        let dir = std::env::temp_dir().join(format!("lsctl-deploy-plan-{}", std::process::id()));
+        let config_file = dir.join("fly.json");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        // The secret cannot be resolved, so generating fly.toml would fail
+        std::fs::write(
+            &config_file,
+            r#"{
+                "name": "api",
+                "organization": "personal",
+                "default_region": "ord",
+                "environment": [
+                    { "key": "API_KEY", "from_dotenv": { "file": "missing.env", "key": "API_KEY" }, "secret": true }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let client = RecordingFlyClient {
+            apps: vec![FlyctlApp {
+                name: "api".to_string(),
+                ..FlyctlApp::default()
+            }],
+            ..RecordingFlyClient::default()
+        };
+        let deploy = FlyDeploy::parse_from(["deploy", config_file.to_str().unwrap(), "--plan"]);
+
+        deploy.deploy(&client).await.unwrap();
+
+        assert_eq!(client.calls(), vec!["apps list", "secrets list api"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn passes_the_config_gen_flags_through() {
+        let deploy =
+            FlyDeploy::parse_from(["deploy", "--allow-tracked", "--allow-missing-secrets"]);
+        let gen = deploy.config_gen_options(deploy.config_file_options.clone());
+
+        assert!(gen.allow_tracked);
+        assert!(gen.secret_options.allow_missing_secrets);
+
+        let deploy = FlyDeploy::parse_from(["deploy"]);
+
+        assert!(
+            !deploy
+                .config_gen_options(deploy.config_file_options.clone())
+                .allow_tracked
+        );
+    }
 }
@@ -1,12 +1,19 @@
-use anyhow;
 use async_trait::async_trait;
-use clap::{Parser, Subcommand};
-use futures::executor::block_on;
+use clap::{Args, Parser, Subcommand};
 use std::collections::HashMap;
 
+use super::{import_fly_toml, import_live_state, ordered};
 use crate::{
+    errors::LsctlError,
     models::fly_models::*,
-    utils::{file_utils, gcp_kms, gcp_ssm},
+    utils::{
+        config_sources::{ConfigFormat, ConfigSources},
+        file_utils,
+        fly_client::Flyctl,
+        git_utils,
+        secret_cache::SecretCache,
+        secret_provider::SecretProviders,
+    },
 };
 use colored::*;
 use schemars::schema_for;
@@ -58,8 +65,14 @@ impl super::CommandRunner for FlyConfigNewOptions {
                 vm_size: FlyVmSize::default(),
             },
             hooks: Some(FlyHooks {
+                pre_launch: None,
+                post_launch: None,
+                post_database_attach: None,
                 pre_deploy: None,
+                pre_scale: None,
                 post_deploy: None,
+                on_failure: None,
+                on_rollback: None,
             }),
             build: None,
             deploy: None,
@@ -69,6 +82,11 @@ impl super::CommandRunner for FlyConfigNewOptions {
             statics: None,
             gcp_kms: None,
             gcp_ssm: None,
+            aws_ssm: None,
+            aws_secrets_manager: None,
+            vault: None,
+            sops: None,
+            dotenv: None,
             database: Some(FlyDatabase {
                 postgres: if *database {
                     Some(FlyDatabasePostgres {
@@ -80,16 +98,27 @@ impl super::CommandRunner for FlyConfigNewOptions {
                     None
                 },
             }),
+            processes: None,
+            metrics: None,
+            http_service: None,
+            vm: None,
+            checks: None,
+            console_command: None,
+            swap_size_mb: None,
+            experimental: None,
+            extra: None,
             environment: Some(vec![
                 EnvironmentVariable {
                     key: "PLAINTEXT_VALUE".to_string(),
                     value: EnvironmentVariableValue::Value("plaintext value".to_string()),
+                    secret: false,
                 },
                 EnvironmentVariable {
                     key: "FROM_GCP_KMS_VALUE".to_string(),
                     value: EnvironmentVariableValue::FromGcpKms {
                         value: "kms string".to_string(),
                     },
+                    secret: true,
                 },
                 EnvironmentVariable {
                     key: "FROM_GCP_SSM_VALUE".to_string(),
@@ -97,9 +126,9 @@ impl super::CommandRunner for FlyConfigNewOptions {
                         name: "ssm name".to_string(),
                         version: 1,
                     },
+                    secret: true,
                 },
             ]),
-            // metrics: None,
             services: Some(vec![FlyService {
                 internal_port: 3000,
                 processes: vec!["app".to_string()],
@@ -136,9 +165,9 @@ impl super::CommandRunner for FlyConfigNewOptions {
             }]),
         };
 
-        let config_json = serde_json::to_string_pretty(&config).unwrap();
+        let config_string = ConfigFormat::of(file).serialize(&config)?;
 
-        return match file_utils::create_and_write_file(file, config_json) {
+        return match file_utils::create_and_write_file(file, config_string) {
             Ok(_) => Ok(()),
             Err(e) => anyhow::bail!("Error creating file: {}", e),
         };
@@ -154,108 +183,222 @@ pub struct FlyConfigGenOptions {
     /// The name of the output Fly toml file
     #[clap(long, short, default_value = "fly.toml")]
     pub output_file: String,
+
+    /// Write placeholders instead of the values of secret-sourced environment variables
+    #[clap(long)]
+    pub redact: bool,
+
+    /// Also write the merged config to this file
+    #[clap(long)]
+    pub merged_file: Option<String>,
+
+    /// Write decrypted values even if the output file is tracked by git
+    #[clap(long)]
+    pub allow_tracked: bool,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+
+    #[clap(flatten)]
+    pub secret_options: SecretOptions,
 }
 
 #[async_trait]
 impl super::CommandRunner for FlyConfigGenOptions {
     async fn execute(&self) -> anyhow::Result<()> {
-        let input_files = &self.input_files;
+        let sources = self.config_file_options.sources(&self.input_files)?;
         let output_file = &self.output_file;
 
         println!("Generating fly config:");
-        println!("    {} {}", "input files".bold(), input_files.join(", "));
+        println!(
+            "    {} {}",
+            "input files".bold(),
+            sources.file_paths().join(", ")
+        );
         println!("    {} {}", "output file".bold(), output_file);
 
-        let deploy_config: DeployConfig = DeployConfig::new(input_files)?;
+        let deploy_config: DeployConfig = DeployConfig::from_sources(&sources)?;
 
         let mut environment_map: HashMap<String, String> = HashMap::new();
 
-        match &deploy_config.environment {
-            Some(env) => {
-                let environment_all = insert_environment_variables(&deploy_config, env);
-                environment_map.extend(environment_all);
+        let environment = deploy_config.plain_environment();
+        let (secret_sourced, plain): (Vec<_>, Vec<_>) = environment
+            .into_iter()
+            .partition(|env_var| env_var.value.is_secret_sourced());
+
+        environment_map.extend(
+            insert_environment_variables(&deploy_config, &plain, &self.secret_options).await?,
+        );
+
+        if self.redact {
+            environment_map.extend(secret_sourced.iter().map(|env_var| {
+                (
+                    env_var.key.to_string(),
+                    format!("<redacted:{}>", env_var.value.source()),
+                )
+            }));
+        } else if !secret_sourced.is_empty() {
+            if git_utils::is_tracked(output_file) && !self.allow_tracked {
+                anyhow::bail!(
+                    "Refusing to write decrypted values to {} because it is tracked by git. Use --redact or --allow-tracked, mark the variables as secret or untrack the file.",
+                    output_file
+                );
             }
-            None => {}
+
+            environment_map.extend(
+                insert_environment_variables(&deploy_config, &secret_sourced, &self.secret_options)
+                    .await?,
+            );
         }
 
         let json_string = serde_json::to_string_pretty(&deploy_config)?;
 
-        let fly_config = FlyConfig {
-            app: deploy_config.name,
-            kill_signal: deploy_config.kill_signal,
-            kill_timeout: deploy_config.kill_timeout,
-            build: deploy_config.build,
-            deploy: deploy_config.deploy,
-            statics: deploy_config.statics,
-            services: deploy_config.services,
-            mounts: deploy_config.mounts,
-            env: Some(environment_map),
-        };
+        let fly_config = FlyConfig::new(deploy_config, environment_map);
+
+        let toml_string = toml::Value::try_from(&fly_config)?.to_string();
 
-        let toml_string = toml::to_string(&fly_config)?;
+        if let Err(e) = file_utils::create_and_write_file(output_file, toml_string) {
+            anyhow::bail!("Error creating file {}: {}", output_file, e);
+        }
+
+        if let Some(merged_file) = &self.merged_file {
+            if git_utils::is_tracked(merged_file) {
+                println!(
+                    "{} {} is tracked by git",
+                    "warning:".yellow().bold(),
+                    merged_file
+                );
+            }
 
-        file_utils::create_and_write_file(output_file, toml_string)
-            .expect(&format!("Error creating file: {}", output_file));
-        file_utils::create_and_write_file("./merged.json", json_string)
-            .expect(&format!("Error creating file: {}", output_file));
+            if let Err(e) = file_utils::create_and_write_file(merged_file, json_string) {
+                anyhow::bail!("Error creating file {}: {}", merged_file, e);
+            }
+        }
 
         anyhow::Ok(())
     }
 }
 
-fn insert_environment_variables(
+/// Options for choosing the config files by environment name instead of listing them.
+#[derive(Clone, Args, Debug)]
+pub struct ConfigFileOptions {
+    /// Use <config-dir>/default.json and <config-dir>/<env>.json instead of the input files
+    #[clap(long)]
+    pub env: Option<String>,
+
+    /// The directory the --env config files are in
+    #[clap(long, default_value = "deploy")]
+    pub config_dir: String,
+
+    /// Override a config value, e.g. --set scaling.max_count=2. Values are parsed as JSON when
+    /// they can be
+    #[clap(long = "set", value_name = "KEY=VALUE", multiple_occurrences = true)]
+    pub overrides: Vec<String>,
+}
+
+impl ConfigFileOptions {
+    pub fn sources(&self, input_files: &[String]) -> anyhow::Result<ConfigSources> {
+        let mut sources = match &self.env {
+            Some(env) => ConfigSources::read_env(&self.config_dir, env)?,
+            None => ConfigSources::read(input_files)?,
+        };
+
+        sources.set(&self.overrides)?;
+
+        Ok(sources)
+    }
+}
+
+/// Options for resolving secret-sourced environment variables.
+#[derive(Clone, Args, Debug)]
+pub struct SecretOptions {
+    /// The number of secrets that are resolved at the same time
+    #[clap(long, default_value = "8")]
+    pub secret_concurrency: usize,
+
+    /// Cache resolved secrets in an encrypted per-user cache for this many seconds
+    #[clap(long)]
+    pub secret_cache_ttl: Option<u64>,
+
+    /// Leave out environment variables whose secrets cannot be resolved instead of failing
+    #[clap(long)]
+    pub allow_missing_secrets: bool,
+}
+
+impl Default for SecretOptions {
+    fn default() -> Self {
+        SecretOptions {
+            secret_concurrency: 8,
+            secret_cache_ttl: None,
+            allow_missing_secrets: false,
+        }
+    }
+}
+
+pub async fn insert_environment_variables(
     deploy_config: &DeployConfig,
-    v: &Vec<EnvironmentVariable>,
-) -> HashMap<String, String> {
+    v: &[EnvironmentVariable],
+    options: &SecretOptions,
+) -> anyhow::Result<HashMap<String, String>> {
     let mut environment: HashMap<String, String> = HashMap::new();
+    let providers = SecretProviders::new(deploy_config);
+
+    let cache = match options.secret_cache_ttl {
+        Some(ttl) => match SecretCache::open(ttl) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                println!("Error opening the secret cache: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
 
-    v.into_iter().for_each(|env_var| match &env_var.value {
-        EnvironmentVariableValue::Value(value) => {
-            environment.insert(String::from(env_var.key.as_str()), value.to_string());
-        }
-        EnvironmentVariableValue::FromGcpKms { value } => block_on(async {
-            let gcp_kms_unwrapped = deploy_config
-                .gcp_kms
-                .as_ref()
-                .expect("gcp_ssm config is not set");
-
-            match gcp_kms::decrypt_ciphertext(
-                gcp_kms_unwrapped.project.as_str(),
-                gcp_kms_unwrapped.location.as_str(),
-                gcp_kms_unwrapped.key_ring.as_str(),
-                gcp_kms_unwrapped.key.as_str(),
-                value.as_str(),
-            ) {
-                Ok(decrypted_value) => {
-                    environment.insert(String::from(env_var.key.as_str()), decrypted_value);
-                }
-                Err(e) => {
-                    println!("Error decrypting {}: {}", value, e);
-                }
+    let values = v.iter().map(|env_var| &env_var.value).collect::<Vec<_>>();
+    let resolved = providers
+        .resolve_all(&values, options.secret_concurrency, cache.as_ref())
+        .await;
+
+    let mut failures: Vec<String> = vec![];
+
+    for env_var in v {
+        match resolved.get(&env_var.value) {
+            Some(Ok(value)) => {
+                environment.insert(String::from(env_var.key.as_str()), value.to_string());
             }
-        }),
-        EnvironmentVariableValue::FromGcpSsm { name, version } => block_on(async {
-            match gcp_ssm::access_secret_version(
-                deploy_config
-                    .gcp_ssm
-                    .as_ref()
-                    .expect("gcp_ssm config is not set")
-                    .project
-                    .as_str(),
-                name.as_str(),
-                *version,
-            ) {
-                Ok(secret_value) => {
-                    environment.insert(String::from(env_var.key.as_str()), secret_value);
-                }
-                Err(e) => {
-                    println!("Error accessing {}/{}: {}", name, version, e);
-                }
+            Some(Err(e)) => {
+                failures.push(format!(
+                    "    {} ({}): {:#}",
+                    env_var.key,
+                    env_var.value.source(),
+                    e
+                ));
             }
-        }),
-    });
+            None => {}
+        }
+    }
+
+    if let Some(cache) = cache {
+        if let Err(e) = cache.save() {
+            println!("Error saving the secret cache: {}", e);
+        }
+    }
+
+    if !failures.is_empty() {
+        let report = format!(
+            "Failed to resolve {} environment variable(s):\n{}",
+            failures.len(),
+            failures.join("\n")
+        );
+
+        if !options.allow_missing_secrets {
+            return Err(LsctlError::Secret(report).into());
+        }
+
+        println!("{} {}", "warning:".yellow().bold(), report);
+    }
 
-    environment
+    Ok(environment)
 }
 
 #[derive(Clone, Parser, Debug)]
@@ -275,16 +418,199 @@ impl super::CommandRunner for FlyConfigSchemaOptions {
 
         let schema = schema_for!(DeployConfig);
 
-        return match file_utils::create_and_write_file(
-            file,
-            serde_json::to_string_pretty(&schema).unwrap(),
-        ) {
+        return match file_utils::create_and_write_file(file, serde_json::to_string_pretty(&schema)?)
+        {
             Ok(_) => Ok(()),
             Err(e) => anyhow::bail!("Error creating file: {}", e),
         };
     }
 }
 
+#[derive(Clone, Parser, Debug)]
+pub struct FlyConfigValidateOptions {
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for FlyConfigValidateOptions {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let sources = self.config_file_options.sources(&self.input_files)?;
+
+        println!("Validating fly config:");
+        println!(
+            "    {} {}",
+            "input files".bold(),
+            sources.file_paths().join(", ")
+        );
+
+        let deploy_config = DeployConfig::from_sources(&sources)?;
+        let problems = super::validate(&deploy_config);
+
+        if problems.is_empty() {
+            println!("No problems found");
+
+            return anyhow::Ok(());
+        }
+
+        let report = problems
+            .iter()
+            .map(|problem| match sources.location(&problem.pointer) {
+                Some(location) => format!("    {} {}", location, problem),
+                None => format!("    {}", problem),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        Err(LsctlError::ConfigInvalid(format!(
+            "Found {} problem(s) in the fly config:\n{}",
+            problems.len(),
+            report
+        ))
+        .into())
+    }
+}
+
+#[derive(Clone, Parser, Debug)]
+pub struct FlyConfigExplainOptions {
+    /// The JSON pointer of the value, e.g. /scaling/min_count
+    pub pointer: String,
+
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for FlyConfigExplainOptions {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let sources = self.config_file_options.sources(&self.input_files)?;
+        let pointer = &self.pointer;
+
+        match sources.rendered()?.pointer(pointer) {
+            Some(value) => println!("{} = {}", pointer.bold(), value),
+            None => println!("{} is not set", pointer.bold()),
+        }
+
+        for (location, value) in sources.chain(pointer) {
+            println!("    {} {}", location, value);
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+#[derive(Clone, Parser, Debug)]
+pub struct FlyConfigImportOptions {
+    /// The fly.toml file to import
+    #[clap(default_value = "fly.toml")]
+    pub input_file: String,
+
+    /// The name of the config file to write
+    #[clap(long, short, default_value = "fly.json")]
+    pub output_file: String,
+
+    /// The organization of the fly app, defaults to the organization of the app on Fly
+    #[clap(long)]
+    pub organization: Option<String>,
+
+    /// The default region of the fly app, defaults to primary_region or the first region of the
+    /// app on Fly
+    #[clap(long)]
+    pub default_region: Option<String>,
+
+    /// Only import the fly.toml file without reading the regions and scaling of the app from Fly
+    #[clap(long)]
+    pub offline: bool,
+
+    /// Overwrite the output file if it exists
+    #[clap(long)]
+    pub force: bool,
+}
+
+#[async_trait]
+impl super::CommandRunner for FlyConfigImportOptions {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let input_file = &self.input_file;
+        let output_file = &self.output_file;
+
+        if file_utils::does_file_exist(output_file) && !self.force {
+            anyhow::bail!(
+                "{} already exists. Use --force to overwrite it.",
+                output_file
+            );
+        }
+
+        println!("Importing fly config:");
+        println!("    {} {}", "input file".bold(), input_file);
+        println!("    {} {}", "output file".bold(), output_file);
+
+        let contents = std::fs::read_to_string(input_file).map_err(|e| LsctlError::ConfigIo {
+            file: input_file.to_string(),
+            source: e,
+        })?;
+        let fly_toml = ConfigFormat::Toml.parse(input_file, &contents)?;
+        let (mut config, extra_keys) = import_fly_toml(&fly_toml)?;
+
+        for key in extra_keys {
+            println!(
+                "{} kept {} under extra because lsctl does not know it",
+                "warning:".yellow().bold(),
+                key
+            );
+        }
+
+        let name = match config.get("name").and_then(|name| name.as_str()) {
+            Some(name) => name.to_string(),
+            None => anyhow::bail!("{} does not have an app name", input_file),
+        };
+
+        if let Some(organization) = &self.organization {
+            config.insert("organization".to_string(), organization.as_str().into());
+        }
+
+        if let Some(default_region) = &self.default_region {
+            config.insert("default_region".to_string(), default_region.as_str().into());
+        }
+
+        if !self.offline {
+            import_live_state(&mut config, &Flyctl, &name)?;
+        }
+
+        for key in ["organization", "default_region"] {
+            if !config.contains_key(key) {
+                anyhow::bail!(
+                    "The {} of {} is not known. Set it with --{}.",
+                    key,
+                    name,
+                    key.replace('_', "-")
+                );
+            }
+        }
+
+        let config = serde_json::Value::Object(ordered(config));
+
+        serde_json::from_value::<DeployConfig>(config.clone()).map_err(|e| {
+            LsctlError::ConfigInvalid(format!("The imported config is not valid: {}", e))
+        })?;
+
+        let config_string = ConfigFormat::of(output_file).serialize(&config)?;
+
+        if let Err(e) = file_utils::create_and_write_file(output_file, config_string + "\n") {
+            anyhow::bail!("Error creating file {}: {}", output_file, e);
+        }
+
+        anyhow::Ok(())
+    }
+}
+
 #[derive(Clone, Subcommand, Debug)]
 pub enum FlyConfigSubcommand {
     /// Generates a new fly config file
@@ -293,4 +619,204 @@ pub enum FlyConfigSubcommand {
     Gen(FlyConfigGenOptions),
     /// Generates the fly config schema
     Schema(FlyConfigSchemaOptions),
+    /// Checks the fly config for mistakes
+    Validate(FlyConfigValidateOptions),
+    /// Shows the merged value at a JSON pointer and the files that set it
+    Explain(FlyConfigExplainOptions),
+    /// Converts an existing fly.toml file and the app on Fly into a fly config
+    Import(FlyConfigImportOptions),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{commands::CommandRunner, errors};
+
+    /// A directory with a fly.json that reads `.env`, and the `.env` file.
+    fn config_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lsctl-gen-{}-{}", name, std::process::id()));
+        let dotenv = |key: &str| json!({ "file": dir.join(".env"), "key": key });
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".env"), "TOKEN=plain-token\nAPI_KEY=secret-key\n").unwrap();
+        fs::write(
+            dir.join("fly.json"),
+            json!({
+                "name": "api",
+                "organization": "personal",
+                "default_region": "ord",
+                "environment": [
+                    { "key": "PLAIN", "value": "plain" },
+                    { "key": "TOKEN", "from_dotenv": dotenv("TOKEN") },
+                    { "key": "API_KEY", "from_dotenv": dotenv("API_KEY"), "secret": true },
+                    { "key": "MISSING", "from_dotenv": dotenv("MISSING") },
+                    { "key": "ALSO_MISSING", "from_dotenv": dotenv("ALSO_MISSING") }
+                ]
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        dir
+    }
+
+    fn gen_options(dir: &Path, flags: &[&str]) -> FlyConfigGenOptions {
+        let input_file = dir.join("fly.json");
+        let output_file = dir.join("fly.toml");
+        let mut args = vec![
+            "gen",
+            input_file.to_str().unwrap(),
+            "-o",
+            output_file.to_str().unwrap(),
+        ];
+        args.extend(flags);
+
+        FlyConfigGenOptions::parse_from(args)
+    }
+
+    fn fly_toml_env(dir: &Path) -> toml::Value {
+        let fly_toml: toml::Value =
+            toml::from_str(&fs::read_to_string(dir.join("fly.toml")).unwrap()).unwrap();
+
+        fly_toml["env"].clone()
+    }
+
+    #[tokio::test]
+    async fn aggregates_secret_failures_unless_missing_secrets_are_allowed() {
+        let dir = config_dir("missing");
+        let error = gen_options(&dir, &[]).execute().await.unwrap_err();
+
+        assert_eq!(errors::exit_code(&error), 6);
+        assert!(matches!(
+            error.downcast_ref::<LsctlError>(),
+            Some(LsctlError::Secret(_))
+        ));
+        assert!(error
+            .to_string()
+            .starts_with("Failed to resolve 2 environment variable(s):"));
+        assert!(!dir.join("fly.toml").exists());
+
+        gen_options(&dir, &["--allow-missing-secrets"])
+            .execute()
+            .await
+            .unwrap();
+
+        let env = fly_toml_env(&dir);
+
+        assert_eq!(env["PLAIN"].as_str(), Some("plain"));
+        assert_eq!(env["TOKEN"].as_str(), Some("plain-token"));
+        assert!(env.get("MISSING").is_none());
+        // Secrets are imported with flyctl and never written to fly.toml
+        assert!(env.get("API_KEY").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn redacts_secret_sourced_values() {
+        let dir = config_dir("redact");
+
+        gen_options(&dir, &["--redact", "--allow-missing-secrets"])
+            .execute()
+            .await
+            .unwrap();
+
+        let env = fly_toml_env(&dir);
+
+        assert_eq!(env["PLAIN"].as_str(), Some("plain"));
+        assert_eq!(env["TOKEN"].as_str(), Some("<redacted:from_dotenv>"));
+        assert_eq!(env["MISSING"].as_str(), Some("<redacted:from_dotenv>"));
+        assert!(env.get("API_KEY").is_none());
+        assert!(!fs::read_to_string(dir.join("fly.toml"))
+            .unwrap()
+            .contains("secret-key"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_to_write_values_into_a_tracked_file() {
+        let dir = config_dir("tracked");
+        let git = |args: &[&str]| {
+            assert!(Command::new("git")
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .unwrap()
+                .status
+                .success());
+        };
+
+        fs::write(dir.join("fly.toml"), "").unwrap();
+        git(&["init", "-q"]);
+        git(&["add", "fly.toml"]);
+
+        let error = gen_options(&dir, &["--allow-missing-secrets"])
+            .execute()
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().starts_with("Refusing to write"));
+        assert_eq!(fs::read_to_string(dir.join("fly.toml")).unwrap(), "");
+
+        gen_options(&dir, &["--allow-missing-secrets", "--redact"])
+            .execute()
+            .await
+            .unwrap();
+        gen_options(&dir, &["--allow-missing-secrets", "--allow-tracked"])
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(fly_toml_env(&dir)["TOKEN"].as_str(), Some("plain-token"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_writes_the_merged_file_when_asked() {
+        let dir = config_dir("merged");
+        let merged_file = dir.join("merged.json");
+
+        gen_options(&dir, &["--allow-missing-secrets"])
+            .execute()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|name| name.ends_with(".json"))
+                .collect::<Vec<_>>(),
+            vec!["fly.json"]
+        );
+
+        gen_options(
+            &dir,
+            &[
+                "--allow-missing-secrets",
+                "--merged-file",
+                merged_file.to_str().unwrap(),
+            ],
+        )
+        .execute()
+        .await
+        .unwrap();
+
+        let merged: DeployConfig =
+            serde_json::from_str(&fs::read_to_string(&merged_file).unwrap()).unwrap();
+
+        assert_eq!(merged.name, "api");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,447 @@
+use async_trait::async_trait;
+use clap::Parser;
+use colored::*;
+
+use crate::{
+    errors::LsctlError,
+    models::{
+        fly_models::DeployConfig,
+        flyctl_models::{FlyctlAutoscale, FlyctlVmSize},
+    },
+    utils::{
+        collection_utils,
+        fly_client::{FlyClient, Flyctl},
+    },
+};
+
+use super::{undeclared_secrets, ConfigFileOptions, FlyAppState};
+
+/// The settings of the app on Fly that `lsctl fly deploy` manages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlyLiveState {
+    pub app_exists: bool,
+    pub regions: Vec<String>,
+    pub backup_regions: Vec<String>,
+    pub vm_size: FlyctlVmSize,
+    pub autoscale: FlyctlAutoscale,
+    /// The number of running instances
+    pub count: u64,
+    pub secrets: Vec<String>,
+    pub postgres_exists: bool,
+}
+
+impl FlyLiveState {
+    /// Fetches the settings on top of the `FlyAppState` a deploy is planned with, so the diff
+    /// and the deploy agree on whether the database is attached.
+    pub fn fetch(
+        client: &dyn FlyClient,
+        deploy_config: &DeployConfig,
+    ) -> anyhow::Result<FlyLiveState> {
+        let name = &deploy_config.name;
+        let app_state = FlyAppState::fetch(client, deploy_config)?;
+
+        if !app_state.app_exists {
+            return Ok(FlyLiveState::default());
+        }
+
+        let regions = client.regions_list(name)?;
+        let status = client.status(name)?;
+
+        Ok(FlyLiveState {
+            app_exists: true,
+            regions: regions
+                .regions
+                .into_iter()
+                .map(|region| region.code)
+                .collect(),
+            backup_regions: regions
+                .backup_regions
+                .into_iter()
+                .map(|region| region.code)
+                .collect(),
+            vm_size: client.scale_show(name)?,
+            autoscale: client.autoscale_show(name)?,
+            count: status
+                .allocations
+                .iter()
+                .filter(|allocation| allocation.status == "running")
+                .count() as u64,
+            secrets: app_state.secrets,
+            postgres_exists: app_state.postgres_exists,
+        })
+    }
+}
+
+/// A setting where the app on Fly differs from the config.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlyDrift {
+    pub field: String,
+    pub live: String,
+    pub config: String,
+}
+
+impl FlyDrift {
+    fn new<L: ToString, C: ToString>(field: &str, live: L, config: C) -> FlyDrift {
+        FlyDrift {
+            field: field.to_string(),
+            live: live.to_string(),
+            config: config.to_string(),
+        }
+    }
+}
+
+/// Compares the config with the app on Fly and returns every setting a deploy would change.
+pub fn diff(deploy_config: &DeployConfig, live: &FlyLiveState) -> Vec<FlyDrift> {
+    let mut drift = vec![];
+
+    if !live.app_exists {
+        drift.push(FlyDrift::new("app", "missing", &deploy_config.name));
+
+        return drift;
+    }
+
+    let scaling = &deploy_config.scaling;
+    let live_balance_method = match (live.autoscale.enabled, live.autoscale.balance_regions) {
+        (false, _) => "static",
+        (true, true) => "balanced",
+        (true, false) => "standard",
+    };
+
+    if live_balance_method != scaling.balance_method.to_string() {
+        drift.push(FlyDrift::new(
+            "scaling.balance_method",
+            live_balance_method,
+            &scaling.balance_method,
+        ));
+    }
+
+    if scaling.balance_method.is_static() {
+        if live.count != scaling.min_count {
+            drift.push(FlyDrift::new(
+                "scaling.count",
+                live.count,
+                scaling.min_count,
+            ));
+        }
+    } else {
+        if live.autoscale.min_count != scaling.min_count {
+            drift.push(FlyDrift::new(
+                "scaling.min_count",
+                live.autoscale.min_count,
+                scaling.min_count,
+            ));
+        }
+
+        if live.autoscale.max_count != scaling.max_count {
+            drift.push(FlyDrift::new(
+                "scaling.max_count",
+                live.autoscale.max_count,
+                scaling.max_count,
+            ));
+        }
+    }
+
+    if live.vm_size.memory_mb != scaling.memory {
+        drift.push(FlyDrift::new(
+            "scaling.memory",
+            live.vm_size.memory_mb,
+            scaling.memory,
+        ));
+    }
+
+    let regions = collection_utils::sorted_unique(
+        deploy_config
+            .regions
+            .iter()
+            .chain(std::iter::once(&deploy_config.default_region)),
+    );
+    let live_regions = collection_utils::sorted_unique(live.regions.iter());
+
+    if live_regions != regions {
+        drift.push(FlyDrift::new(
+            "regions",
+            live_regions.join(", "),
+            regions.join(", "),
+        ));
+    }
+
+    let backup_regions = collection_utils::sorted_unique(deploy_config.backup_regions.iter());
+    let live_backup_regions = collection_utils::sorted_unique(live.backup_regions.iter());
+
+    if live_backup_regions != backup_regions {
+        drift.push(FlyDrift::new(
+            "backup_regions",
+            live_backup_regions.join(", "),
+            backup_regions.join(", "),
+        ));
+    }
+
+    let managed_secrets = deploy_config.managed_secrets();
+    let secrets = collection_utils::sorted_unique(
+        deploy_config
+            .secret_environment()
+            .iter()
+            .map(|env_var| &env_var.key),
+    );
+    // Undeclared secrets are only unset with --prune-secrets, see `undeclared_secrets`
+    let live_secrets = collection_utils::sorted_unique(
+        live.secrets
+            .iter()
+            .filter(|secret| secrets.contains(secret)),
+    );
+
+    if live_secrets != secrets {
+        drift.push(FlyDrift::new(
+            "secrets",
+            live_secrets.join(", "),
+            secrets.join(", "),
+        ));
+    }
+
+    if !managed_secrets.is_empty() {
+        let postgres_app = format!("{}-postgres", deploy_config.name);
+
+        if !live.secrets.contains(&"DATABASE_URL".to_string()) {
+            drift.push(FlyDrift::new(
+                "database.postgres",
+                if live.postgres_exists {
+                    format!("{} not attached", postgres_app)
+                } else {
+                    "missing".to_string()
+                },
+                format!("{} attached", postgres_app),
+            ));
+        }
+    }
+
+    drift
+}
+
+#[derive(Clone, Parser, Debug)]
+pub struct FlyDiff {
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for FlyDiff {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let deploy_config =
+            DeployConfig::from_sources(&self.config_file_options.sources(&self.input_files)?)?;
+        let live = FlyLiveState::fetch(&Flyctl, &deploy_config)?;
+        let drift = diff(&deploy_config, &live);
+        let undeclared_secrets = undeclared_secrets(&deploy_config, &live.secrets);
+
+        if !undeclared_secrets.is_empty() {
+            println!(
+                "{} {} would be removed with --prune-secrets",
+                "warning:".yellow().bold(),
+                undeclared_secrets.join(", ")
+            );
+        }
+
+        if drift.is_empty() {
+            println!("{} matches the config", deploy_config.name.bold());
+
+            return anyhow::Ok(());
+        }
+
+        println!("Drift for {}:", deploy_config.name.bold());
+
+        for drift in &drift {
+            println!("    {}", drift.field.bold());
+            println!("        {}", format!("- {}", drift.live).red());
+            println!("        {}", format!("+ {}", drift.config).green());
+        }
+
+        Err(LsctlError::Drift(format!(
+            "{} setting(s) of {} differ from the config",
+            drift.len(),
+            deploy_config.name
+        ))
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        commands::{FlyDeployPlan, FlyDeployStep},
+        models::flyctl_models::{FlyctlApp, FlyctlRegion, FlyctlRegions, FlyctlSecret},
+        utils::fly_client::{FlyDeployArgs, RecordingFlyClient},
+    };
+
+    fn deploy_config() -> DeployConfig {
+        serde_json::from_value(json!({
+            "name": "api",
+            "organization": "personal",
+            "default_region": "ord",
+            "regions": ["iad"],
+            "backup_regions": ["sea"],
+            "scaling": {
+                "memory": 512,
+                "min_count": 1,
+                "max_count": 3,
+                "balance_method": "standard"
+            },
+            "database": { "postgres": { "vm_size": "shared-cpu-1x", "volume_size": 1 } },
+            "environment": [{ "key": "SECRET_KEY", "value": "secret", "secret": true }]
+        }))
+        .unwrap()
+    }
+
+    fn client() -> RecordingFlyClient {
+        let regions = |codes: &[&str]| {
+            codes
+                .iter()
+                .map(|code| FlyctlRegion {
+                    code: code.to_string(),
+                    ..FlyctlRegion::default()
+                })
+                .collect()
+        };
+        let names = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| FlyctlApp {
+                    name: name.to_string(),
+                    ..FlyctlApp::default()
+                })
+                .collect()
+        };
+
+        RecordingFlyClient {
+            apps: names(&["api", "api-postgres"]),
+            postgres: names(&["api-postgres"]),
+            secrets: ["DATABASE_URL", "SECRET_KEY"]
+                .iter()
+                .map(|name| FlyctlSecret {
+                    name: name.to_string(),
+                    ..FlyctlSecret::default()
+                })
+                .collect(),
+            regions: FlyctlRegions {
+                regions: regions(&["iad", "ord"]),
+                backup_regions: regions(&["sea"]),
+            },
+            vm_size: FlyctlVmSize {
+                name: "shared-cpu-1x".to_string(),
+                memory_mb: 512,
+            },
+            autoscale: FlyctlAutoscale {
+                enabled: true,
+                min_count: 1,
+                max_count: 3,
+                balance_regions: false,
+            },
+            ..RecordingFlyClient::default()
+        }
+    }
+
+    #[test]
+    fn finds_no_drift_when_the_app_matches() {
+        let deploy_config = deploy_config();
+        let client = client();
+        let live = FlyLiveState::fetch(&client, &deploy_config).unwrap();
+
+        assert_eq!(diff(&deploy_config, &live), vec![]);
+        assert!(!client.calls().contains(&"postgres list".to_string()));
+    }
+
+    #[test]
+    fn reports_settings_changed_by_hand() {
+        let mut client = client();
+        client.autoscale.max_count = 6;
+        client.vm_size.memory_mb = 1024;
+        client.regions.regions.pop();
+        client.vm_size.name = "dedicated-cpu-1x".to_string();
+        client.secrets = vec![FlyctlSecret {
+            name: "MANUAL".to_string(),
+            ..FlyctlSecret::default()
+        }];
+
+        let deploy_config = deploy_config();
+        let live = FlyLiveState::fetch(&client, &deploy_config).unwrap();
+
+        assert_eq!(
+            live.postgres_exists,
+            FlyAppState::fetch(&client, &deploy_config)
+                .unwrap()
+                .postgres_exists
+        );
+        assert_eq!(
+            diff(&deploy_config, &live),
+            vec![
+                FlyDrift::new("scaling.max_count", 6, 3),
+                FlyDrift::new("scaling.memory", 1024, 512),
+                FlyDrift::new("regions", "iad", "iad, ord"),
+                FlyDrift::new("secrets", "", "SECRET_KEY"),
+                FlyDrift::new(
+                    "database.postgres",
+                    "api-postgres not attached",
+                    "api-postgres attached"
+                ),
+            ]
+        );
+        assert_eq!(
+            undeclared_secrets(&deploy_config, &live.secrets),
+            vec!["MANUAL"]
+        );
+    }
+
+    #[test]
+    fn finds_no_drift_after_a_deploy() {
+        let deploy_config = deploy_config();
+        let plan = FlyDeployPlan::new(
+            &deploy_config,
+            &FlyAppState::default(),
+            &FlyDeployArgs::default(),
+        );
+        let mut live = FlyLiveState {
+            vm_size: FlyctlVmSize {
+                name: "dedicated-cpu-1x".to_string(),
+                memory_mb: 256,
+            },
+            secrets: vec!["MANUAL".to_string()],
+            ..FlyLiveState::default()
+        };
+
+        for step in plan.steps {
+            match step {
+                FlyDeployStep::Launch { .. } => live.app_exists = true,
+                FlyDeployStep::PostgresCreate { .. } => live.postgres_exists = true,
+                FlyDeployStep::PostgresAttach { .. } => {
+                    live.secrets.push("DATABASE_URL".to_string())
+                }
+                FlyDeployStep::SecretsImport { keys, .. } => live.secrets.extend(keys),
+                FlyDeployStep::ScaleCount { count, .. } => live.count = count,
+                FlyDeployStep::Autoscale {
+                    balance_method,
+                    min_count,
+                    max_count,
+                    ..
+                } => {
+                    live.autoscale = FlyctlAutoscale {
+                        enabled: true,
+                        min_count,
+                        max_count,
+                        balance_regions: balance_method == "balanced",
+                    }
+                }
+                FlyDeployStep::ScaleMemory { memory, .. } => live.vm_size.memory_mb = memory,
+                FlyDeployStep::RegionsSet { regions, .. } => live.regions = regions,
+                FlyDeployStep::RegionsBackup { regions, .. } => live.backup_regions = regions,
+                _ => {}
+            }
+        }
+
+        assert_eq!(diff(&deploy_config, &live), vec![]);
+    }
+}
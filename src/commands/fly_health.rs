@@ -0,0 +1,228 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use colored::*;
+
+use crate::{
+    models::fly_models::{DeployConfig, FlyServiceHttpCheck, FlyServicePortHandler},
+    utils::fly_client::{FlyClient, HttpProbe, HttpProbeResponse},
+};
+
+/// How long `lsctl fly deploy` waits for the health checks to pass by default, in seconds.
+pub static DEFAULT_HEALTH_CHECK_TIMEOUT: u64 = 300;
+
+static POLL_INTERVAL: Duration = Duration::from_secs(5);
+static DEFAULT_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An `http_checks` entry of the config, sent to the public hostname of the app.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthCheck {
+    pub scheme: String,
+    pub method: String,
+    pub path: String,
+    pub headers: BTreeMap<String, String>,
+    pub timeout: Duration,
+}
+
+impl HealthCheck {
+    fn new(scheme: &str, check: &FlyServiceHttpCheck) -> HealthCheck {
+        HealthCheck {
+            scheme: scheme.to_string(),
+            method: check.method.as_deref().unwrap_or("GET").to_uppercase(),
+            path: check.path.as_deref().unwrap_or("/").to_string(),
+            headers: check
+                .headers
+                .iter()
+                .flatten()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            timeout: check
+                .timeout
+                .as_deref()
+                .and_then(parse_duration)
+                .unwrap_or(DEFAULT_CHECK_TIMEOUT),
+        }
+    }
+}
+
+/// The HTTP checks of the services and the http_service of the config.
+pub fn health_checks(deploy_config: &DeployConfig) -> Vec<HealthCheck> {
+    let services = deploy_config.services.iter().flatten();
+    let has_tls = services
+        .clone()
+        .flat_map(|service| service.ports.iter())
+        .any(|port| port.handlers.contains(&FlyServicePortHandler::Tls));
+    let scheme = if has_tls || deploy_config.http_service.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+
+    services
+        .flat_map(|service| service.http_checks.iter().flatten())
+        .chain(
+            deploy_config
+                .http_service
+                .iter()
+                .flat_map(|http_service| http_service.checks.iter().flatten()),
+        )
+        .map(|check| HealthCheck::new(scheme, check))
+        .collect()
+}
+
+/// Parses check durations like `2s`, `500ms` or `1m`. Plain numbers are milliseconds.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (value, unit) = duration.split_at(unit_start);
+    let value: u64 = value.parse().ok()?;
+
+    match unit {
+        "" | "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_secs(value * 60)),
+        _ => None,
+    }
+}
+
+/// Sends every check to every region until they all pass or the timeout, in seconds, is up. A
+/// check only passes for a region when that region answered it.
+pub fn verify(
+    client: &dyn FlyClient,
+    app: &str,
+    regions: &[String],
+    checks: &[HealthCheck],
+    timeout: u64,
+) -> anyhow::Result<()> {
+    let hostname = client
+        .status(app)?
+        .hostname
+        .unwrap_or_else(|| format!("{}.fly.dev", app));
+    let deadline = Instant::now() + Duration::from_secs(timeout);
+
+    let mut pending = regions
+        .iter()
+        .flat_map(|region| checks.iter().map(move |check| (region, check)))
+        .collect::<Vec<_>>();
+
+    loop {
+        let mut failing = vec![];
+
+        for (region, check) in pending {
+            let probe = HttpProbe {
+                url: format!("{}://{}{}", check.scheme, hostname, check.path),
+                method: check.method.to_string(),
+                headers: check.headers.clone(),
+                region: region.to_string(),
+                timeout: check.timeout,
+            };
+            let label = format!("    {} {} {}", region.bold(), check.method, check.path);
+
+            match client.probe(&probe) {
+                Ok(response) if !(200..400).contains(&response.status) => {
+                    failing.push((region, check, label, response.status.to_string()))
+                }
+                Ok(HttpProbeResponse {
+                    status,
+                    region: Some(served_by),
+                }) if &served_by == region => println!("{} {}", label, status.to_string().green()),
+                Ok(HttpProbeResponse {
+                    status,
+                    region: served_by,
+                }) => failing.push((
+                    region,
+                    check,
+                    label,
+                    format!(
+                        "{} (answered by {})",
+                        status,
+                        served_by.as_deref().unwrap_or("an unknown region")
+                    ),
+                )),
+                Err(e) => failing.push((region, check, label, e.to_string())),
+            }
+        }
+
+        if failing.is_empty() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+
+        if now >= deadline {
+            for (_, _, label, result) in &failing {
+                println!("{} {}", label, result.red());
+            }
+
+            anyhow::bail!(
+                "{} health check(s) did not pass within {} seconds",
+                failing.len(),
+                timeout
+            );
+        }
+
+        std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+
+        pending = failing
+            .into_iter()
+            .map(|(region, check, _, _)| (region, check))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fly_client::RecordingFlyClient;
+
+    #[test]
+    fn parses_check_durations() {
+        assert_eq!(parse_duration("2000"), Some(Duration::from_millis(2000)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("5s"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_duration("1m"), Some(Duration::from_secs(60)));
+        assert_eq!(parse_duration("soon"), None);
+    }
+
+    #[test]
+    fn fails_checks_not_answered_by_their_region() {
+        let checks = [HealthCheck {
+            scheme: "https".to_string(),
+            method: "GET".to_string(),
+            path: "/health".to_string(),
+            headers: BTreeMap::new(),
+            timeout: DEFAULT_CHECK_TIMEOUT,
+        }];
+        let regions = ["ord".to_string(), "iad".to_string()];
+        let client = RecordingFlyClient {
+            answering_region: Some(Some("ord".to_string())),
+            ..RecordingFlyClient::default()
+        };
+
+        assert_eq!(
+            verify(&client, "api", &regions, &checks, 0)
+                .unwrap_err()
+                .to_string(),
+            "1 health check(s) did not pass within 0 seconds"
+        );
+        assert!(verify(&client, "api", &regions[..1], &checks, 0).is_ok());
+
+        assert!(verify(&RecordingFlyClient::default(), "api", &regions, &checks, 0).is_ok());
+
+        let client = RecordingFlyClient {
+            answering_region: Some(None),
+            ..RecordingFlyClient::default()
+        };
+
+        assert_eq!(
+            verify(&client, "api", &regions, &checks, 0)
+                .unwrap_err()
+                .to_string(),
+            "2 health check(s) did not pass within 0 seconds"
+        );
+    }
+}
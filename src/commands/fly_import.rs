@@ -0,0 +1,411 @@
+use schemars::schema_for;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    models::fly_models::{DeployConfig, FlyAutoscalingBalanceMethod, FlyVmSize},
+    utils::fly_client::FlyClient,
+};
+
+/// fly.toml keys that have a different name in the config.
+static RENAMED_KEYS: &[(&str, &str)] = &[
+    ("app", "name"),
+    ("primary_region", "default_region"),
+    ("env", "environment"),
+];
+
+/// The keys that are written first to the imported config, in this order.
+static LEADING_KEYS: &[&str] = &[
+    "name",
+    "organization",
+    "default_region",
+    "regions",
+    "backup_regions",
+    "scaling",
+];
+
+/// Converts a parsed fly.toml into a config. Sections the config cannot represent are kept under
+/// `extra` so `fly config gen` writes them back as they are. Returns the config and the fly.toml
+/// keys that were kept under `extra`.
+pub fn import_fly_toml(fly_toml: &Value) -> anyhow::Result<(Map<String, Value>, Vec<String>)> {
+    let fly_toml = match fly_toml {
+        Value::Object(fly_toml) => fly_toml,
+        _ => anyhow::bail!("The fly.toml file is not a table"),
+    };
+
+    let schema = schema_for!(DeployConfig);
+    let config_keys = &schema.schema.object.as_ref().unwrap().properties;
+    let mut config = Map::new();
+    let mut extra = Map::new();
+
+    for (key, value) in fly_toml {
+        let config_key = RENAMED_KEYS
+            .iter()
+            .find(|(toml_key, _)| toml_key == key)
+            .map(|(_, config_key)| config_key.to_string())
+            .unwrap_or_else(|| key.to_string());
+
+        let config_value = match config_key.as_str() {
+            "environment" => import_env(value),
+            "kill_signal" => import_kill_signal(value),
+            _ => value.clone(),
+        };
+
+        if config_key != "extra"
+            && config_keys.contains_key(&config_key)
+            && accepts(&config_key, &config_value)
+        {
+            config.insert(config_key, config_value);
+        } else {
+            extra.insert(key.to_string(), value.clone());
+        }
+    }
+
+    let extra_keys = extra.keys().cloned().collect();
+
+    if !extra.is_empty() {
+        config.insert("extra".to_string(), Value::Object(extra));
+    }
+
+    Ok((config, extra_keys))
+}
+
+/// Whether the config can hold the value at the top-level key.
+fn accepts(key: &str, value: &Value) -> bool {
+    let mut config = json!({ "name": "app", "organization": "org", "default_region": "ord" });
+    config[key] = value.clone();
+
+    serde_json::from_value::<DeployConfig>(config).is_ok()
+}
+
+/// Turns the `[env]` table into `environment` entries.
+fn import_env(env: &Value) -> Value {
+    match env {
+        Value::Object(env) => Value::Array(
+            env.iter()
+                .map(|(key, value)| {
+                    let value = match value {
+                        Value::String(value) => value.to_string(),
+                        value => value.to_string(),
+                    };
+
+                    json!({ "key": key, "value": value })
+                })
+                .collect(),
+        ),
+        env => env.clone(),
+    }
+}
+
+/// fly.toml has signals like `SIGINT` where the config has `sigInt`.
+fn import_kill_signal(kill_signal: &Value) -> Value {
+    match kill_signal
+        .as_str()
+        .and_then(|s| s.to_ascii_lowercase().strip_prefix("sig").map(String::from))
+    {
+        Some(name) if !name.is_empty() => {
+            let mut chars = name.chars();
+            let first = chars.next().unwrap_or_default().to_ascii_uppercase();
+
+            json!(format!("sig{}{}", first, chars.as_str()))
+        }
+        _ => kill_signal.clone(),
+    }
+}
+
+/// Sets the organization, regions and scaling of the config from the app on Fly. Values already
+/// in the config, like `default_region` from `primary_region`, are kept.
+pub fn import_live_state(
+    config: &mut Map<String, Value>,
+    client: &dyn FlyClient,
+    app: &str,
+) -> anyhow::Result<()> {
+    let fly_app = client
+        .apps_list()?
+        .into_iter()
+        .find(|fly_app| fly_app.name == app);
+
+    let fly_app = match fly_app {
+        Some(fly_app) => fly_app,
+        None => anyhow::bail!("The Fly app {} does not exist", app),
+    };
+
+    if let Some(organization) = fly_app.organization {
+        config
+            .entry("organization")
+            .or_insert_with(|| json!(organization.slug));
+    }
+
+    let regions = client.regions_list(app)?;
+    let mut region_codes = regions.regions.into_iter().map(|region| region.code);
+
+    if !config.contains_key("default_region") {
+        if let Some(region) = region_codes.next() {
+            config.insert("default_region".to_string(), json!(region));
+        }
+    }
+
+    let default_region = config.get("default_region").cloned();
+
+    config.insert(
+        "regions".to_string(),
+        json!(region_codes
+            .filter(|region| Some(&json!(region)) != default_region.as_ref())
+            .collect::<Vec<_>>()),
+    );
+    config.insert(
+        "backup_regions".to_string(),
+        json!(regions
+            .backup_regions
+            .into_iter()
+            .map(|region| region.code)
+            .collect::<Vec<_>>()),
+    );
+
+    let vm_size = client.scale_show(app)?;
+    let autoscale = client.autoscale_show(app)?;
+
+    let balance_method = if !autoscale.enabled {
+        FlyAutoscalingBalanceMethod::Static
+    } else if autoscale.balance_regions {
+        FlyAutoscalingBalanceMethod::Balanced
+    } else {
+        FlyAutoscalingBalanceMethod::Standard
+    };
+
+    let mut scaling = json!({
+        "memory": vm_size.memory_mb,
+        "min_count": autoscale.min_count,
+        "max_count": autoscale.max_count,
+        "balance_method": balance_method.to_string(),
+    });
+
+    if serde_json::from_value::<FlyVmSize>(json!(vm_size.name)).is_ok() {
+        scaling["vm_size"] = json!(vm_size.name);
+    } else {
+        println!(
+            "Skipping VM size {} because it is not supported",
+            vm_size.name
+        );
+    }
+
+    config.insert("scaling".to_string(), scaling);
+
+    Ok(())
+}
+
+/// Puts the identifying keys of the config first so the written file reads well.
+pub fn ordered(config: Map<String, Value>) -> Map<String, Value> {
+    let mut ordered = Map::new();
+
+    for key in LEADING_KEYS {
+        if let Some(value) = config.get(*key) {
+            ordered.insert(key.to_string(), value.clone());
+        }
+    }
+
+    ordered.extend(
+        config
+            .into_iter()
+            .filter(|(key, _)| !LEADING_KEYS.contains(&key.as_str())),
+    );
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{
+            fly_models::{EnvironmentVariableValue, FlyConfig},
+            flyctl_models::{
+                FlyctlApp, FlyctlAutoscale, FlyctlOrganization, FlyctlRegion, FlyctlRegions,
+                FlyctlVmSize,
+            },
+        },
+        utils::{config_sources::ConfigFormat, fly_client::RecordingFlyClient},
+    };
+
+    fn regions(codes: &[&str]) -> Vec<FlyctlRegion> {
+        codes
+            .iter()
+            .map(|code| FlyctlRegion {
+                code: code.to_string(),
+                ..FlyctlRegion::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_unknown_sections_under_extra() {
+        let fly_toml = ConfigFormat::Toml
+            .parse(
+                "fly.toml",
+                r#"
+app = "api"
+kill_signal = "SIGINT"
+kill_timeout = 5
+swap_size_mb = 512
+
+[env]
+PORT = "8080"
+WORKERS = 2
+
+[metrics]
+port = 9091
+path = "/metrics"
+
+[[restart]]
+policy = "always"
+
+[[services]]
+internal_port = 8080
+processes = ["app"]
+
+[services.concurrency]
+type = "connections"
+
+[[services.ports]]
+port = 443
+handlers = ["tls", "http"]
+"#,
+            )
+            .unwrap();
+
+        let (config, extra_keys) = import_fly_toml(&fly_toml).unwrap();
+
+        assert_eq!(extra_keys, vec!["restart"]);
+        assert_eq!(config["name"], json!("api"));
+        assert_eq!(config["kill_signal"], json!("sigInt"));
+        assert_eq!(
+            config["environment"],
+            json!([
+                { "key": "PORT", "value": "8080" },
+                { "key": "WORKERS", "value": "2" }
+            ])
+        );
+        assert_eq!(
+            config["extra"],
+            json!({ "restart": [{ "policy": "always" }] })
+        );
+        assert_eq!(
+            config["metrics"],
+            json!({ "port": 9091, "path": "/metrics" })
+        );
+        assert_eq!(config["swap_size_mb"], json!(512));
+        assert_eq!(config["services"][0]["ports"][0]["port"], json!(443));
+    }
+
+    #[test]
+    fn round_trips_fly_toml() {
+        let contents = r#"
+app = "api"
+primary_region = "ord"
+console_command = "/app/bin/console"
+swap_size_mb = 512
+
+[experimental]
+auto_rollback = true
+
+[processes]
+app = "bin/server"
+worker = "bin/worker"
+
+[http_service]
+internal_port = 8080
+force_https = true
+auto_stop_machines = true
+min_machines_running = 1
+processes = ["app"]
+
+[checks.health]
+type = "http"
+port = 8080
+path = "/health"
+interval = "15s"
+
+[metrics]
+port = 9091
+path = "/metrics"
+
+[[vm]]
+size = "shared-cpu-1x"
+memory = "1gb"
+processes = ["worker"]
+
+[[restart]]
+policy = "always"
+
+[env]
+PORT = "8080"
+"#;
+        let fly_toml = ConfigFormat::Toml.parse("fly.toml", contents).unwrap();
+        let (mut config, _) = import_fly_toml(&fly_toml).unwrap();
+        config.insert("organization".to_string(), json!("personal"));
+
+        let deploy_config: DeployConfig = serde_json::from_value(Value::Object(config)).unwrap();
+        let env = deploy_config
+            .plain_environment()
+            .into_iter()
+            .map(|env_var| match env_var.value {
+                EnvironmentVariableValue::Value(value) => (env_var.key, value),
+                _ => unreachable!(),
+            })
+            .collect();
+        let fly_config = FlyConfig::new(deploy_config, env);
+
+        assert_eq!(
+            toml::Value::try_from(&fly_config).unwrap(),
+            toml::from_str::<toml::Value>(contents).unwrap()
+        );
+    }
+
+    #[test]
+    fn imports_regions_and_scaling_from_fly() {
+        let client = RecordingFlyClient {
+            apps: vec![FlyctlApp {
+                name: "api".to_string(),
+                organization: Some(FlyctlOrganization {
+                    slug: "personal".to_string(),
+                }),
+                ..FlyctlApp::default()
+            }],
+            regions: FlyctlRegions {
+                regions: regions(&["ord", "iad"]),
+                backup_regions: regions(&["sea"]),
+            },
+            vm_size: FlyctlVmSize {
+                name: "shared-cpu-1x".to_string(),
+                memory_mb: 512,
+            },
+            autoscale: FlyctlAutoscale {
+                enabled: true,
+                min_count: 1,
+                max_count: 3,
+                balance_regions: false,
+            },
+            ..RecordingFlyClient::default()
+        };
+
+        let mut config = Map::new();
+        config.insert("name".to_string(), json!("api"));
+        import_live_state(&mut config, &client, "api").unwrap();
+
+        assert_eq!(
+            Value::Object(ordered(config)),
+            json!({
+                "name": "api",
+                "organization": "personal",
+                "default_region": "ord",
+                "regions": ["iad"],
+                "backup_regions": ["sea"],
+                "scaling": {
+                    "memory": 512,
+                    "min_count": 1,
+                    "max_count": 3,
+                    "balance_method": "standard",
+                    "vm_size": "shared-cpu-1x"
+                }
+            })
+        );
+    }
+}
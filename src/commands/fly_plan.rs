@@ -0,0 +1,1332 @@
+use std::{collections::BTreeMap, fmt, process::Command, time::Duration};
+
+use colored::*;
+
+use super::{fly_health, HealthCheck, DEFAULT_HEALTH_CHECK_TIMEOUT};
+use crate::{
+    errors::{self, LsctlError},
+    models::fly_models::{DeployConfig, FlyHookCommand},
+    utils::{
+        collection_utils, command_utils,
+        fly_client::{FlyClient, FlyDeployArgs, Flyctl, FLYCTL},
+    },
+};
+
+/// What is currently known about the app on Fly. Used to decide which steps are needed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlyAppState {
+    pub app_exists: bool,
+    pub postgres_exists: bool,
+    pub secrets: Vec<String>,
+}
+
+impl FlyAppState {
+    pub fn fetch(
+        client: &dyn FlyClient,
+        deploy_config: &DeployConfig,
+    ) -> anyhow::Result<FlyAppState> {
+        let name = &deploy_config.name;
+        let app_exists = client.apps_list()?.iter().any(|app| &app.name == name);
+
+        let secrets = if app_exists {
+            client
+                .secrets_list(name)?
+                .into_iter()
+                .map(|secret| secret.name)
+                .collect::<Vec<_>>()
+        } else {
+            vec![]
+        };
+
+        let needs_postgres = deploy_config
+            .database
+            .as_ref()
+            .and_then(|database| database.postgres.as_ref())
+            .is_some()
+            && !secrets.contains(&"DATABASE_URL".to_string());
+
+        let postgres_exists = if needs_postgres {
+            let postgres_app = format!("{}-postgres", name);
+
+            client
+                .postgres_list()?
+                .iter()
+                .any(|postgres| postgres.name == postgres_app)
+        } else {
+            false
+        };
+
+        Ok(FlyAppState {
+            app_exists,
+            postgres_exists,
+            secrets,
+        })
+    }
+}
+
+/// The secrets on Fly that the config does not declare and `--prune-secrets` unsets. Secrets Fly
+/// manages, like `DATABASE_URL`, are not included and nothing is when the config declares no
+/// secrets.
+pub fn undeclared_secrets(deploy_config: &DeployConfig, secrets: &[String]) -> Vec<String> {
+    let secret_keys = deploy_config
+        .secret_environment()
+        .into_iter()
+        .map(|env_var| env_var.key)
+        .collect::<Vec<_>>();
+    let managed_secrets = deploy_config.managed_secrets();
+
+    if secret_keys.is_empty() {
+        return vec![];
+    }
+
+    collection_utils::sorted_unique(
+        secrets
+            .iter()
+            .filter(|secret| !secret_keys.contains(secret) && !managed_secrets.contains(secret)),
+    )
+}
+
+/// The names of the steps a deploy can have, in the order they run.
+pub static STEP_NAMES: &[&str] = &[
+    "pre-launch",
+    "launch",
+    "post-launch",
+    "postgres-create",
+    "postgres-attach",
+    "post-database-attach",
+    "secrets-import",
+    "secrets-unset",
+    "pre-deploy",
+    "deploy",
+    "pre-scale",
+    "scale-count",
+    "autoscale",
+    "scale-memory",
+    "regions-set",
+    "regions-backup",
+    "health-check",
+    "post-deploy",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlyDeployStep {
+    Launch {
+        app: String,
+        organization: String,
+        region: String,
+    },
+    PostgresCreate {
+        postgres_app: String,
+        organization: String,
+        region: String,
+        volume_size: u64,
+        vm_size: String,
+        cluster_size: u64,
+    },
+    PostgresAttach {
+        postgres_app: String,
+        app: String,
+    },
+    SecretsImport {
+        app: String,
+        keys: Vec<String>,
+        /// Import the resolved secrets when some could not be resolved, instead of failing
+        allow_missing: bool,
+    },
+    SecretsUnset {
+        app: String,
+        keys: Vec<String>,
+    },
+    Hook {
+        phase: String,
+        hook: FlyHookCommand,
+    },
+    Deploy {
+        region: String,
+        args: FlyDeployArgs,
+    },
+    ScaleCount {
+        app: String,
+        count: u64,
+    },
+    Autoscale {
+        app: String,
+        balance_method: String,
+        min_count: u64,
+        max_count: u64,
+    },
+    ScaleMemory {
+        app: String,
+        memory: u64,
+    },
+    RegionsSet {
+        app: String,
+        regions: Vec<String>,
+    },
+    RegionsBackup {
+        app: String,
+        regions: Vec<String>,
+    },
+    HealthCheck {
+        app: String,
+        regions: Vec<String>,
+        checks: Vec<HealthCheck>,
+        /// How long failing checks are retried for, in seconds
+        timeout: u64,
+    },
+}
+
+impl FlyDeployStep {
+    /// A short name for the step, e.g. `secrets-import`.
+    pub fn name(&self) -> String {
+        match self {
+            FlyDeployStep::Launch { .. } => "launch".to_string(),
+            FlyDeployStep::PostgresCreate { .. } => "postgres-create".to_string(),
+            FlyDeployStep::PostgresAttach { .. } => "postgres-attach".to_string(),
+            FlyDeployStep::SecretsImport { .. } => "secrets-import".to_string(),
+            FlyDeployStep::SecretsUnset { .. } => "secrets-unset".to_string(),
+            FlyDeployStep::Hook { phase, .. } => phase.to_string(),
+            FlyDeployStep::Deploy { .. } => "deploy".to_string(),
+            FlyDeployStep::ScaleCount { .. } => "scale-count".to_string(),
+            FlyDeployStep::Autoscale { .. } => "autoscale".to_string(),
+            FlyDeployStep::ScaleMemory { .. } => "scale-memory".to_string(),
+            FlyDeployStep::RegionsSet { .. } => "regions-set".to_string(),
+            FlyDeployStep::RegionsBackup { .. } => "regions-backup".to_string(),
+            FlyDeployStep::HealthCheck { .. } => "health-check".to_string(),
+        }
+    }
+
+    /// The message printed before the step is run.
+    pub fn description(&self) -> String {
+        match self {
+            FlyDeployStep::Launch { .. } => "Launching new app".to_string(),
+            FlyDeployStep::PostgresCreate { .. } => "Creating new Postgres database".to_string(),
+            FlyDeployStep::PostgresAttach { .. } => "Attaching the Postgres database".to_string(),
+            FlyDeployStep::SecretsImport { keys, .. } => {
+                format!("Importing app secrets {}", keys.join(", "))
+            }
+            FlyDeployStep::SecretsUnset { keys, .. } => {
+                format!("Unsetting undeclared app secrets {}", keys.join(", "))
+            }
+            FlyDeployStep::Hook { phase, .. } => format!("Running {} hook", phase),
+            FlyDeployStep::Deploy { .. } => "Deploying the app".to_string(),
+            FlyDeployStep::ScaleCount { count, .. } => {
+                format!("Updating app scaling to {}", count)
+            }
+            FlyDeployStep::Autoscale {
+                balance_method,
+                min_count,
+                max_count,
+                ..
+            } => format!(
+                "Updating app autoscaling to method: {}, min: {}, max: {}",
+                balance_method, min_count, max_count
+            ),
+            FlyDeployStep::ScaleMemory { memory, .. } => {
+                format!("Updating app memory to {}mb", memory)
+            }
+            FlyDeployStep::RegionsSet { regions, .. } => {
+                format!("Updating app regions to {}", regions.join(", "))
+            }
+            FlyDeployStep::RegionsBackup { regions, .. } => {
+                format!("Updating app backup regions {}", regions.join(", "))
+            }
+            FlyDeployStep::HealthCheck { regions, .. } => {
+                format!("Checking the app health in {}", regions.join(", "))
+            }
+        }
+    }
+
+    /// The program and arguments that are run for the step. Health checks are sent by lsctl
+    /// itself and are shown as `http-check` with the requests.
+    pub fn command(&self) -> (String, Vec<String>) {
+        let args = match self {
+            FlyDeployStep::Launch {
+                app,
+                organization,
+                region,
+            } => Flyctl::launch_args(app, organization, region),
+            FlyDeployStep::PostgresCreate {
+                postgres_app,
+                organization,
+                region,
+                volume_size,
+                vm_size,
+                cluster_size,
+            } => Flyctl::postgres_create_args(
+                postgres_app,
+                organization,
+                region,
+                *volume_size,
+                vm_size,
+                *cluster_size,
+            ),
+            FlyDeployStep::PostgresAttach { postgres_app, app } => {
+                Flyctl::postgres_attach_args(postgres_app, app)
+            }
+            FlyDeployStep::SecretsImport { app, .. } => Flyctl::secrets_import_args(app),
+            FlyDeployStep::SecretsUnset { app, keys } => Flyctl::secrets_unset_args(app, keys),
+            FlyDeployStep::Hook { hook, .. } => {
+                return match &hook.args {
+                    Some(args) => (hook.command.to_string(), args.clone()),
+                    None => (
+                        "sh".to_string(),
+                        vec!["-c".to_string(), hook.command.to_string()],
+                    ),
+                };
+            }
+            FlyDeployStep::Deploy { region, args } => Flyctl::deploy_args(region, args),
+            FlyDeployStep::ScaleCount { app, count } => Flyctl::scale_count_args(app, *count),
+            FlyDeployStep::Autoscale {
+                app,
+                balance_method,
+                min_count,
+                max_count,
+            } => Flyctl::autoscale_args(app, balance_method, *min_count, *max_count),
+            FlyDeployStep::ScaleMemory { app, memory } => Flyctl::scale_memory_args(app, *memory),
+            FlyDeployStep::RegionsSet { app, regions } => Flyctl::regions_set_args(app, regions),
+            FlyDeployStep::RegionsBackup { app, regions } => {
+                Flyctl::regions_backup_args(app, regions)
+            }
+            FlyDeployStep::HealthCheck {
+                regions,
+                checks,
+                timeout,
+                ..
+            } => {
+                let mut args = vec![
+                    "--regions".to_string(),
+                    regions.join(","),
+                    "--timeout".to_string(),
+                    format!("{}s", timeout),
+                ];
+                args.extend(
+                    checks
+                        .iter()
+                        .map(|check| format!("{} {}", check.method, check.path)),
+                );
+
+                return ("http-check".to_string(), args);
+            }
+        };
+
+        (FLYCTL.to_string(), args)
+    }
+
+    /// Runs the step, sending every Fly operation through the given client. `secret_values`
+    /// holds the resolved values of the secrets declared in the config.
+    pub fn apply(
+        &self,
+        client: &dyn FlyClient,
+        secret_values: &BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        match self {
+            FlyDeployStep::Launch {
+                app,
+                organization,
+                region,
+            } => client.launch(app, organization, region),
+            FlyDeployStep::PostgresCreate {
+                postgres_app,
+                organization,
+                region,
+                volume_size,
+                vm_size,
+                cluster_size,
+            } => client.postgres_create(
+                postgres_app,
+                organization,
+                region,
+                *volume_size,
+                vm_size,
+                *cluster_size,
+            ),
+            FlyDeployStep::PostgresAttach { postgres_app, app } => {
+                client.postgres_attach(postgres_app, app)
+            }
+            FlyDeployStep::SecretsImport {
+                app,
+                keys,
+                allow_missing,
+            } => {
+                let missing = keys
+                    .iter()
+                    .filter(|key| !secret_values.contains_key(*key))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                if !missing.is_empty() {
+                    let message =
+                        format!("The secret(s) {} could not be resolved", missing.join(", "));
+
+                    if !allow_missing {
+                        return Err(LsctlError::Secret(message).into());
+                    }
+
+                    println!(
+                        "{} {}, they are left as they are on Fly",
+                        "warning:".yellow().bold(),
+                        message
+                    );
+                }
+
+                let secrets = keys
+                    .iter()
+                    .filter_map(|key| {
+                        secret_values
+                            .get(key)
+                            .map(|value| (key.to_string(), value.to_string()))
+                    })
+                    .collect::<BTreeMap<_, _>>();
+
+                if secrets.is_empty() {
+                    return Ok(());
+                }
+
+                client.secrets_import(app, &secrets)
+            }
+            FlyDeployStep::SecretsUnset { app, keys } => client.secrets_unset(app, keys),
+            FlyDeployStep::Hook { phase, hook } => {
+                let (program, args) = self.command();
+                let mut command = Command::new(program);
+                command.args(args).envs(&hook.env);
+
+                if let Some(cwd) = &hook.cwd {
+                    command.current_dir(cwd);
+                }
+
+                let failure_message = format!("Failed to run {} hook", phase);
+                let result = match hook.timeout {
+                    Some(timeout) => command_utils::stream_stdout_with_timeout_or_bail(
+                        &mut command,
+                        Duration::from_secs(timeout),
+                        &failure_message,
+                    ),
+                    None => command_utils::stream_stdout_or_bail(&mut command, &failure_message),
+                };
+
+                match result {
+                    Err(e) if hook.continue_on_error => {
+                        println!("{}", format!("{}, continuing", e).yellow());
+
+                        Ok(())
+                    }
+                    result => result.map(|_| ()),
+                }
+            }
+            FlyDeployStep::Deploy { region, args } => client.deploy(region, args),
+            FlyDeployStep::ScaleCount { app, count } => client.scale_count(app, *count),
+            FlyDeployStep::Autoscale {
+                app,
+                balance_method,
+                min_count,
+                max_count,
+            } => client.autoscale(app, balance_method, *min_count, *max_count),
+            FlyDeployStep::ScaleMemory { app, memory } => client.scale_memory(app, *memory),
+            FlyDeployStep::RegionsSet { app, regions } => client.regions_set(app, regions),
+            FlyDeployStep::RegionsBackup { app, regions } => client.regions_backup(app, regions),
+            FlyDeployStep::HealthCheck {
+                app,
+                regions,
+                checks,
+                timeout,
+            } => fly_health::verify(client, app, regions, checks, *timeout),
+        }
+    }
+}
+
+impl fmt::Display for FlyDeployStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (program, args) = self.command();
+
+        write!(f, "{}", program)?;
+
+        for arg in args {
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                write!(f, " {:?}", arg)?;
+            } else {
+                write!(f, " {}", arg)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The environment variables every hook gets: `LSCTL_APP`, `LSCTL_REGION` and, when it is known,
+/// `LSCTL_IMAGE`.
+fn hook_environment(
+    deploy_config: &DeployConfig,
+    deploy_args: &FlyDeployArgs,
+) -> BTreeMap<String, String> {
+    let mut env = BTreeMap::from([
+        ("LSCTL_APP".to_string(), deploy_config.name.to_string()),
+        (
+            "LSCTL_REGION".to_string(),
+            deploy_config.default_region.to_string(),
+        ),
+    ]);
+
+    let image = deploy_args.image.as_ref().or_else(|| {
+        deploy_config
+            .build
+            .as_ref()
+            .and_then(|build| build.image.as_ref())
+    });
+
+    if let Some(image) = image {
+        env.insert("LSCTL_IMAGE".to_string(), image.to_string());
+    }
+
+    env
+}
+
+/// A step for every hook of the phase. The `env` of a hook takes precedence over `hook_env`.
+fn hook_steps(
+    deploy_config: &DeployConfig,
+    phase: &str,
+    hook_env: &BTreeMap<String, String>,
+) -> Vec<FlyDeployStep> {
+    deploy_config
+        .hooks
+        .as_ref()
+        .and_then(|hooks| hooks.phase(phase))
+        .map(|hooks| hooks.hooks())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|mut hook| {
+            let mut env = hook_env.clone();
+            env.extend(hook.env);
+            hook.env = env;
+
+            FlyDeployStep::Hook {
+                phase: phase.to_string(),
+                hook,
+            }
+        })
+        .collect()
+}
+
+/// The name of the step a deploy failed at.
+pub fn failed_step(error: &anyhow::Error) -> Option<String> {
+    error.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(LsctlError::Flyctl { step, .. }) => Some(step.to_string()),
+        _ => None,
+    })
+}
+
+/// The ordered list of steps `lsctl fly deploy` runs for a config and the current app state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlyDeployPlan {
+    pub app: String,
+    pub steps: Vec<FlyDeployStep>,
+}
+
+impl FlyDeployPlan {
+    pub fn new(
+        deploy_config: &DeployConfig,
+        app_state: &FlyAppState,
+        deploy_args: &FlyDeployArgs,
+    ) -> FlyDeployPlan {
+        let name = &deploy_config.name;
+        let hook_env = hook_environment(deploy_config, deploy_args);
+        let mut steps = vec![];
+
+        if !app_state.app_exists {
+            steps.extend(hook_steps(deploy_config, "pre-launch", &hook_env));
+            steps.push(FlyDeployStep::Launch {
+                app: name.to_string(),
+                organization: deploy_config.organization.to_string(),
+                region: deploy_config.default_region.to_string(),
+            });
+            steps.extend(hook_steps(deploy_config, "post-launch", &hook_env));
+        }
+
+        if let Some(postgres) = deploy_config
+            .database
+            .as_ref()
+            .and_then(|database| database.postgres.as_ref())
+        {
+            let postgres_app = format!("{}-postgres", name);
+            let should_attach_postgres = !app_state.secrets.contains(&"DATABASE_URL".to_string());
+
+            if should_attach_postgres && !app_state.postgres_exists {
+                steps.push(FlyDeployStep::PostgresCreate {
+                    postgres_app: postgres_app.to_string(),
+                    organization: deploy_config.organization.to_string(),
+                    region: deploy_config.default_region.to_string(),
+                    volume_size: postgres.volume_size,
+                    vm_size: postgres.vm_size.to_string(),
+                    cluster_size: postgres.cluster_size,
+                });
+            }
+
+            if should_attach_postgres {
+                steps.push(FlyDeployStep::PostgresAttach {
+                    postgres_app,
+                    app: name.to_string(),
+                });
+                steps.extend(hook_steps(deploy_config, "post-database-attach", &hook_env));
+            }
+        }
+
+        let secret_keys = collection_utils::sorted_unique(
+            deploy_config
+                .secret_environment()
+                .iter()
+                .map(|env_var| &env_var.key),
+        );
+
+        if !secret_keys.is_empty() {
+            steps.push(FlyDeployStep::SecretsImport {
+                app: name.to_string(),
+                keys: secret_keys,
+                allow_missing: false,
+            });
+        }
+
+        steps.extend(hook_steps(deploy_config, "pre-deploy", &hook_env));
+
+        steps.push(FlyDeployStep::Deploy {
+            region: deploy_config.default_region.to_string(),
+            args: deploy_args.clone(),
+        });
+
+        steps.extend(hook_steps(deploy_config, "pre-scale", &hook_env));
+
+        let scaling = &deploy_config.scaling;
+
+        if scaling.balance_method.is_static() {
+            steps.push(FlyDeployStep::ScaleCount {
+                app: name.to_string(),
+                count: scaling.min_count,
+            });
+        } else {
+            steps.push(FlyDeployStep::Autoscale {
+                app: name.to_string(),
+                balance_method: scaling.balance_method.to_string(),
+                min_count: scaling.min_count,
+                max_count: scaling.max_count,
+            });
+        }
+
+        steps.push(FlyDeployStep::ScaleMemory {
+            app: name.to_string(),
+            memory: scaling.memory,
+        });
+
+        let regions = collection_utils::sorted_unique(
+            deploy_config
+                .regions
+                .iter()
+                .chain(std::iter::once(&deploy_config.default_region)),
+        );
+
+        steps.push(FlyDeployStep::RegionsSet {
+            app: name.to_string(),
+            regions: regions.clone(),
+        });
+
+        steps.push(FlyDeployStep::RegionsBackup {
+            app: name.to_string(),
+            regions: collection_utils::sorted_unique(deploy_config.backup_regions.iter()),
+        });
+
+        let checks = fly_health::health_checks(deploy_config);
+
+        if !checks.is_empty() {
+            steps.push(FlyDeployStep::HealthCheck {
+                app: name.to_string(),
+                regions,
+                checks,
+                timeout: DEFAULT_HEALTH_CHECK_TIMEOUT,
+            });
+        }
+
+        steps.extend(hook_steps(deploy_config, "post-deploy", &hook_env));
+
+        FlyDeployPlan {
+            app: name.to_string(),
+            steps,
+        }
+    }
+
+    /// The steps that redeploy an earlier image and reconcile regions and scaling like a deploy
+    /// does, leaving the app, its database and its secrets as they are. The `on_rollback` hooks
+    /// run last.
+    pub fn rollback(deploy_config: &DeployConfig, image: &str) -> FlyDeployPlan {
+        let app_state = FlyAppState {
+            app_exists: true,
+            postgres_exists: true,
+            secrets: deploy_config.managed_secrets(),
+        };
+        let deploy_args = FlyDeployArgs {
+            image: Some(image.to_string()),
+            ..FlyDeployArgs::default()
+        };
+        let mut plan = FlyDeployPlan::new(deploy_config, &app_state, &deploy_args);
+
+        plan.steps.retain(|step| {
+            matches!(
+                step,
+                FlyDeployStep::Deploy { .. }
+                    | FlyDeployStep::ScaleCount { .. }
+                    | FlyDeployStep::Autoscale { .. }
+                    | FlyDeployStep::ScaleMemory { .. }
+                    | FlyDeployStep::RegionsSet { .. }
+                    | FlyDeployStep::RegionsBackup { .. }
+                    | FlyDeployStep::HealthCheck { .. }
+            )
+        });
+        plan.steps.extend(hook_steps(
+            deploy_config,
+            "on-rollback",
+            &hook_environment(deploy_config, &deploy_args),
+        ));
+
+        plan
+    }
+
+    /// The `on_failure` hooks to run after the step failed.
+    pub fn on_failure(
+        deploy_config: &DeployConfig,
+        deploy_args: &FlyDeployArgs,
+        failed_step: Option<&str>,
+    ) -> FlyDeployPlan {
+        let mut hook_env = hook_environment(deploy_config, deploy_args);
+
+        if let Some(failed_step) = failed_step {
+            hook_env.insert("LSCTL_FAILED_STEP".to_string(), failed_step.to_string());
+        }
+
+        FlyDeployPlan {
+            app: deploy_config.name.to_string(),
+            steps: hook_steps(deploy_config, "on-failure", &hook_env),
+        }
+    }
+
+    /// Sets how long the health checks are retried for, in seconds, or leaves them out when
+    /// `None`.
+    pub fn set_health_check_timeout(&mut self, health_check_timeout: Option<u64>) {
+        match health_check_timeout {
+            Some(health_check_timeout) => {
+                for step in &mut self.steps {
+                    if let FlyDeployStep::HealthCheck { timeout, .. } = step {
+                        *timeout = health_check_timeout;
+                    }
+                }
+            }
+            None => self
+                .steps
+                .retain(|step| !matches!(step, FlyDeployStep::HealthCheck { .. })),
+        }
+    }
+
+    /// Unsets the `undeclared_secrets` right after importing the declared ones.
+    pub fn prune_secrets(&mut self, deploy_config: &DeployConfig, app_state: &FlyAppState) {
+        let import_index = match self
+            .steps
+            .iter()
+            .position(|step| matches!(step, FlyDeployStep::SecretsImport { .. }))
+        {
+            Some(import_index) => import_index,
+            None => return,
+        };
+
+        let undeclared_secrets = undeclared_secrets(deploy_config, &app_state.secrets);
+
+        if !undeclared_secrets.is_empty() {
+            self.steps.insert(
+                import_index + 1,
+                FlyDeployStep::SecretsUnset {
+                    app: self.app.to_string(),
+                    keys: undeclared_secrets,
+                },
+            );
+        }
+    }
+
+    /// Sets whether secrets that could not be resolved are left out of the import instead of
+    /// failing the deploy.
+    pub fn set_allow_missing_secrets(&mut self, allow_missing_secrets: bool) {
+        for step in &mut self.steps {
+            if let FlyDeployStep::SecretsImport { allow_missing, .. } = step {
+                *allow_missing = allow_missing_secrets;
+            }
+        }
+    }
+
+    /// Keeps the steps named in `only`, when it is not empty, and drops the steps named in
+    /// `skip`.
+    pub fn select(&mut self, only: &[String], skip: &[String]) {
+        self.steps.retain(|step| {
+            let name = step.name();
+
+            (only.is_empty() || only.contains(&name)) && !skip.contains(&name)
+        });
+    }
+
+    /// Runs every step in order, stopping at the first failure.
+    pub fn apply(
+        &self,
+        client: &dyn FlyClient,
+        secret_values: &BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.apply_with_progress(client, secret_values, &mut |_| Ok(()))
+    }
+
+    /// Runs every step in order like `apply`, calling `completed` after each step that succeeds.
+    pub fn apply_with_progress(
+        &self,
+        client: &dyn FlyClient,
+        secret_values: &BTreeMap<String, String>,
+        completed: &mut dyn FnMut(&FlyDeployStep) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        for step in &self.steps {
+            println!("{}", step.description());
+
+            step.apply(client, secret_values).map_err(|e| {
+                // Secrets that could not be resolved keep their own exit code
+                if let Some(LsctlError::Secret(_)) = e.downcast_ref() {
+                    return e;
+                }
+
+                LsctlError::Flyctl {
+                    step: step.name(),
+                    exit_code: errors::command_exit_code(&e),
+                    source: e,
+                }
+                .into()
+            })?;
+
+            completed(step)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for FlyDeployPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Deploy plan for {}:", self.app)?;
+
+        for (index, step) in self.steps.iter().enumerate() {
+            writeln!(f, "  {:>2}. {}", index + 1, step.description())?;
+            writeln!(f, "      {}", step)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        models::flyctl_models::{FlyctlApp, FlyctlSecret},
+        utils::fly_client::RecordingFlyClient,
+    };
+
+    fn apps(names: &[&str]) -> Vec<FlyctlApp> {
+        names
+            .iter()
+            .map(|name| FlyctlApp {
+                name: name.to_string(),
+                ..FlyctlApp::default()
+            })
+            .collect()
+    }
+
+    fn secrets(names: &[&str]) -> Vec<FlyctlSecret> {
+        names
+            .iter()
+            .map(|name| FlyctlSecret {
+                name: name.to_string(),
+                ..FlyctlSecret::default()
+            })
+            .collect()
+    }
+
+    fn deploy_config(overrides: serde_json::Value) -> DeployConfig {
+        let mut config = json!({
+            "name": "api",
+            "organization": "personal",
+            "default_region": "ord",
+            "regions": ["lax", "iad"],
+            "backup_regions": ["sea", "dfw"],
+            "scaling": {
+                "memory": 512,
+                "min_count": 1,
+                "max_count": 5,
+                "balance_method": "standard"
+            },
+            "database": {
+                "postgres": {
+                    "vm_size": "shared-cpu-1x",
+                    "volume_size": 1
+                }
+            }
+        });
+
+        json_patch::merge(&mut config, &overrides);
+
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn deploy(client: &RecordingFlyClient, deploy_config: &DeployConfig) -> anyhow::Result<()> {
+        let app_state = FlyAppState::fetch(client, deploy_config)?;
+        let deploy_args = FlyDeployArgs {
+            image: Some("registry.fly.io/api:1".to_string()),
+            ..FlyDeployArgs::default()
+        };
+
+        let secret_values = BTreeMap::from([
+            ("API_KEY".to_string(), "value".to_string()),
+            ("SECRET_KEY".to_string(), "value".to_string()),
+        ]);
+
+        FlyDeployPlan::new(deploy_config, &app_state, &deploy_args).apply(client, &secret_values)
+    }
+
+    #[test]
+    fn launches_new_app_with_database() {
+        let client = RecordingFlyClient {
+            apps: apps(&["api-staging"]),
+            postgres: apps(&["api-postgres-old"]),
+            ..RecordingFlyClient::default()
+        };
+
+        deploy(&client, &deploy_config(json!({}))).unwrap();
+
+        assert_eq!(
+            client.calls(),
+            vec![
+                "apps list",
+                "postgres list",
+                "launch api personal ord",
+                "postgres create api-postgres personal ord 1 shared-cpu-1x 2",
+                "postgres attach api-postgres api",
+                "deploy ord registry.fly.io/api:1",
+                "autoscale api standard 1 5",
+                "scale memory api 512",
+                "regions set api iad,lax,ord",
+                "regions backup api dfw,sea",
+            ]
+        );
+    }
+
+    #[test]
+    fn updates_existing_app_with_static_scaling() {
+        let client = RecordingFlyClient {
+            apps: apps(&["api", "api-postgres"]),
+            secrets: secrets(&["DATABASE_URL"]),
+            ..RecordingFlyClient::default()
+        };
+
+        let deploy_config = deploy_config(json!({
+            "scaling": { "balance_method": "static", "min_count": 3 }
+        }));
+
+        deploy(&client, &deploy_config).unwrap();
+
+        assert_eq!(
+            client.calls(),
+            vec![
+                "apps list",
+                "secrets list api",
+                "deploy ord registry.fly.io/api:1",
+                "scale count api 3",
+                "scale memory api 512",
+                "regions set api iad,lax,ord",
+                "regions backup api dfw,sea",
+            ]
+        );
+    }
+
+    #[test]
+    fn attaches_existing_database() {
+        let client = RecordingFlyClient {
+            apps: apps(&["api"]),
+            postgres: apps(&["api-postgres"]),
+            secrets: secrets(&["SECRET_KEY"]),
+            ..RecordingFlyClient::default()
+        };
+
+        deploy(&client, &deploy_config(json!({}))).unwrap();
+
+        assert_eq!(client.calls()[2], "postgres list");
+        assert_eq!(client.calls()[3], "postgres attach api-postgres api");
+        assert!(!client
+            .calls()
+            .iter()
+            .any(|call| call.starts_with("postgres create")));
+    }
+
+    #[test]
+    fn imports_declared_secrets_and_only_unsets_the_rest_when_pruning() {
+        let client = RecordingFlyClient {
+            apps: apps(&["api"]),
+            secrets: secrets(&["DATABASE_URL", "OLD_KEY", "SECRET_KEY"]),
+            ..RecordingFlyClient::default()
+        };
+        let app_state = FlyAppState::fetch(&client, &deploy_config(json!({}))).unwrap();
+        let step_names = |deploy_config: &DeployConfig, prune: bool| {
+            let mut plan = FlyDeployPlan::new(deploy_config, &app_state, &FlyDeployArgs::default());
+
+            if prune {
+                plan.prune_secrets(deploy_config, &app_state);
+            }
+
+            plan.steps[..3].to_vec()
+        };
+
+        let deploy_config = deploy_config(json!({
+            "environment": [
+                { "key": "PLAIN", "value": "plain" },
+                { "key": "SECRET_KEY", "value": "secret", "secret": true },
+                { "key": "API_KEY", "from_gcp_ssm": { "name": "api-key", "version": 1 }, "secret": true }
+            ]
+        }));
+        let import = FlyDeployStep::SecretsImport {
+            app: "api".to_string(),
+            keys: vec!["API_KEY".to_string(), "SECRET_KEY".to_string()],
+            allow_missing: false,
+        };
+
+        assert_eq!(step_names(&deploy_config, false)[0], import);
+        assert_eq!(step_names(&deploy_config, false)[1].name(), "deploy");
+        assert_eq!(
+            step_names(&deploy_config, true)[..2],
+            [
+                import,
+                FlyDeployStep::SecretsUnset {
+                    app: "api".to_string(),
+                    keys: vec!["OLD_KEY".to_string()],
+                },
+            ]
+        );
+
+        // Without declared secrets nothing is unset, even when pruning
+        let no_secrets = self::deploy_config(json!({}));
+
+        assert_eq!(step_names(&no_secrets, true)[0].name(), "deploy");
+    }
+
+    #[test]
+    fn fails_when_a_declared_secret_is_not_resolved() {
+        let client = RecordingFlyClient::default();
+        let mut step = FlyDeployStep::SecretsImport {
+            app: "api".to_string(),
+            keys: vec!["API_KEY".to_string(), "SECRET_KEY".to_string()],
+            allow_missing: false,
+        };
+        let secret_values = BTreeMap::from([("SECRET_KEY".to_string(), "value".to_string())]);
+        let plan = FlyDeployPlan {
+            app: "api".to_string(),
+            steps: vec![step.clone()],
+        };
+        let error = plan.apply(&client, &secret_values).unwrap_err();
+
+        assert_eq!(errors::exit_code(&error), 6);
+        assert_eq!(
+            error.to_string(),
+            "The secret(s) API_KEY could not be resolved"
+        );
+        assert!(client.calls().is_empty());
+
+        if let FlyDeployStep::SecretsImport { allow_missing, .. } = &mut step {
+            *allow_missing = true;
+        }
+
+        step.apply(&client, &secret_values).unwrap();
+
+        assert_eq!(client.calls(), vec!["secrets import api SECRET_KEY"]);
+    }
+
+    #[test]
+    fn checks_health_before_the_post_deploy_hook() {
+        let deploy_config = deploy_config(json!({
+            "regions": ["iad"],
+            "backup_regions": [],
+            "database": null,
+            "hooks": { "post_deploy": "true" },
+            "services": [{
+                "internal_port": 3000,
+                "processes": ["app"],
+                "concurrency": { "type": "connections" },
+                "ports": [{ "port": 443, "handlers": ["tls", "http"] }],
+                "http_checks": [{ "path": "/api/health", "method": "get" }]
+            }]
+        }));
+        let apply = |client: &RecordingFlyClient| {
+            let app_state = FlyAppState::fetch(client, &deploy_config)?;
+            let mut plan =
+                FlyDeployPlan::new(&deploy_config, &app_state, &FlyDeployArgs::default());
+            plan.set_health_check_timeout(Some(0));
+
+            plan.apply(client, &BTreeMap::new())
+        };
+
+        let client = RecordingFlyClient {
+            apps: apps(&["api"]),
+            ..RecordingFlyClient::default()
+        };
+
+        apply(&client).unwrap();
+
+        assert_eq!(
+            client.calls()[7..],
+            [
+                "status api",
+                "probe iad GET https://api.fly.dev/api/health",
+                "probe ord GET https://api.fly.dev/api/health",
+            ]
+        );
+
+        let client = RecordingFlyClient {
+            apps: apps(&["api"]),
+            unhealthy_regions: vec!["iad".to_string()],
+            ..RecordingFlyClient::default()
+        };
+        let error = apply(&client).unwrap_err();
+
+        assert_eq!(error.to_string(), "The health-check step failed");
+        assert_eq!(
+            client.calls().last().unwrap(),
+            "probe ord GET https://api.fly.dev/api/health"
+        );
+    }
+
+    #[test]
+    fn runs_every_hook_with_the_deploy_environment() {
+        let dir = std::env::temp_dir().join(format!("lsctl-hooks-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let deploy_config = deploy_config(json!({
+            "database": null,
+            "hooks": {
+                "pre_deploy": [
+                    {
+                        "command": "echo \"$LSCTL_APP $LSCTL_IMAGE $STAGE\" > hook.txt && exit 3",
+                        "env": { "STAGE": "migrate" },
+                        "cwd": dir,
+                        "continue_on_error": true
+                    },
+                    { "command": "false", "args": [] }
+                ],
+                "post_deploy": "true"
+            }
+        }));
+        let client = RecordingFlyClient {
+            apps: apps(&["api"]),
+            ..RecordingFlyClient::default()
+        };
+        let error = deploy(&client, &deploy_config).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "The pre-deploy step failed with exit code 1"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("hook.txt")).unwrap(),
+            "api registry.fly.io/api:1 migrate\n"
+        );
+        assert_eq!(client.calls(), vec!["apps list", "secrets list api"]);
+
+        let hook = FlyDeployStep::Hook {
+            phase: "post-deploy".to_string(),
+            hook: FlyHookCommand {
+                command: "sleep 5".to_string(),
+                timeout: Some(0),
+                ..FlyHookCommand::default()
+            },
+        };
+
+        assert_eq!(hook.to_string(), "sh -c \"sleep 5\"");
+        assert!(hook
+            .apply(&client, &BTreeMap::new())
+            .unwrap_err()
+            .to_string()
+            .contains("did not finish within 0 seconds"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn places_hooks_around_the_steps_of_their_phase() {
+        let phases = [
+            "pre_launch",
+            "post_launch",
+            "post_database_attach",
+            "pre_deploy",
+            "pre_scale",
+            "post_deploy",
+            "on_failure",
+            "on_rollback",
+        ];
+        let hooks = phases
+            .iter()
+            .map(|phase| (phase.to_string(), json!("true")))
+            .collect::<serde_json::Map<_, _>>();
+        let deploy_config = deploy_config(json!({ "hooks": hooks }));
+        let deploy_args = FlyDeployArgs {
+            image: Some("registry.fly.io/api:2".to_string()),
+            ..FlyDeployArgs::default()
+        };
+        let step_names = |plan: FlyDeployPlan| {
+            plan.steps
+                .iter()
+                .map(FlyDeployStep::name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            step_names(FlyDeployPlan::new(
+                &deploy_config,
+                &FlyAppState::default(),
+                &deploy_args
+            )),
+            vec![
+                "pre-launch",
+                "launch",
+                "post-launch",
+                "postgres-create",
+                "postgres-attach",
+                "post-database-attach",
+                "pre-deploy",
+                "deploy",
+                "pre-scale",
+                "autoscale",
+                "scale-memory",
+                "regions-set",
+                "regions-backup",
+                "post-deploy",
+            ]
+        );
+        assert_eq!(
+            step_names(FlyDeployPlan::rollback(
+                &deploy_config,
+                "registry.fly.io/api:1"
+            ))
+            .last()
+            .unwrap(),
+            "on-rollback"
+        );
+
+        let on_failure = FlyDeployPlan::on_failure(&deploy_config, &deploy_args, Some("deploy"));
+
+        match &on_failure.steps[..] {
+            [FlyDeployStep::Hook { phase, hook }] => {
+                assert_eq!(phase, "on-failure");
+                assert_eq!(
+                    hook.env,
+                    BTreeMap::from([
+                        ("LSCTL_APP".to_string(), "api".to_string()),
+                        ("LSCTL_FAILED_STEP".to_string(), "deploy".to_string()),
+                        (
+                            "LSCTL_IMAGE".to_string(),
+                            "registry.fly.io/api:2".to_string()
+                        ),
+                        ("LSCTL_REGION".to_string(), "ord".to_string()),
+                    ])
+                );
+            }
+            steps => panic!("unexpected on-failure steps {:?}", steps),
+        }
+    }
+
+    #[test]
+    fn selects_steps_by_name() {
+        let app_state = FlyAppState {
+            app_exists: true,
+            secrets: vec!["DATABASE_URL".to_string()],
+            ..FlyAppState::default()
+        };
+        let plan = FlyDeployPlan::new(
+            &deploy_config(json!({})),
+            &app_state,
+            &FlyDeployArgs::default(),
+        );
+        let step_names = |only: &[&str], skip: &[&str]| {
+            let to_strings = |names: &[&str]| {
+                names
+                    .iter()
+                    .map(|name| name.to_string())
+                    .collect::<Vec<_>>()
+            };
+            let mut plan = plan.clone();
+            plan.select(&to_strings(only), &to_strings(skip));
+
+            plan.steps
+                .iter()
+                .map(FlyDeployStep::name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            step_names(&["autoscale", "scale-memory"], &[]),
+            vec!["autoscale", "scale-memory"]
+        );
+        assert_eq!(
+            step_names(&[], &["deploy", "regions-backup"]),
+            vec!["autoscale", "scale-memory", "regions-set"]
+        );
+    }
+
+    #[test]
+    fn prints_the_steps_and_their_commands() {
+        let app_state = FlyAppState {
+            app_exists: true,
+            secrets: vec!["DATABASE_URL".to_string()],
+            ..FlyAppState::default()
+        };
+        let deploy_config = deploy_config(json!({
+            "regions": [],
+            "backup_regions": [],
+            "hooks": { "pre_deploy": "npm run migrate" }
+        }));
+        let deploy_args = FlyDeployArgs {
+            image: Some("registry.fly.io/api:1".to_string()),
+            ..FlyDeployArgs::default()
+        };
+
+        assert_eq!(
+            FlyDeployPlan::new(&deploy_config, &app_state, &deploy_args).to_string(),
+            [
+                "Deploy plan for api:",
+                "   1. Running pre-deploy hook",
+                "      sh -c \"npm run migrate\"",
+                "   2. Deploying the app",
+                "      flyctl deploy --region ord --image registry.fly.io/api:1",
+                "   3. Updating app autoscaling to method: standard, min: 1, max: 5",
+                "      flyctl autoscale standard --app api min=1 max=5",
+                "   4. Updating app memory to 512mb",
+                "      flyctl scale memory 512 --app api",
+                "   5. Updating app regions to ord",
+                "      flyctl regions set ord --app api",
+                "   6. Updating app backup regions ",
+                "      flyctl regions backup --app api",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn stops_at_first_failed_step() {
+        let client = RecordingFlyClient {
+            apps: apps(&["api"]),
+            secrets: secrets(&["DATABASE_URL"]),
+            fail_on: Some("deploy".to_string()),
+            ..RecordingFlyClient::default()
+        };
+
+        assert!(deploy(&client, &deploy_config(json!({}))).is_err());
+        assert_eq!(
+            client.calls().last().unwrap(),
+            "deploy ord registry.fly.io/api:1"
+        );
+    }
+}
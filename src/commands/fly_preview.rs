@@ -0,0 +1,353 @@
+use async_trait::async_trait;
+use clap::{Parser, Subcommand};
+use colored::*;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    models::{fly_models::DeployConfig, flyctl_models::FlyctlApp},
+    utils::{
+        config_template::slugify,
+        fly_client::{FlyClient, Flyctl},
+    },
+};
+
+use super::ConfigFileOptions;
+
+/// The longest app name Fly accepts.
+static MAX_APP_NAME_LENGTH: usize = 63;
+
+/// How many bytes of the branch hash end the names of shortened preview apps.
+static PREVIEW_HASH_BYTES: usize = 3;
+
+/// The prefix every preview app of the config's app starts with.
+pub fn preview_prefix(name: &str) -> String {
+    format!("{}-preview-", name)
+}
+
+/// The app name for the preview of a branch, e.g. `api-preview-feature-login` for
+/// `feature/login`. It is shortened so the name of its Postgres app still fits, and then ends
+/// with a hash of the branch so long branches that share a prefix get different apps.
+pub fn preview_app_name(name: &str, branch: &str) -> anyhow::Result<String> {
+    let slug = slugify(branch);
+
+    if slug.is_empty() {
+        anyhow::bail!("The branch name has no characters that can be used in an app name");
+    }
+
+    let max_length = MAX_APP_NAME_LENGTH - postgres_app_name("").len();
+    let app = format!("{}{}", preview_prefix(name), slug);
+
+    if app.chars().count() <= max_length {
+        return Ok(app);
+    }
+
+    let hash = Sha256::digest(branch.as_bytes())
+        .iter()
+        .take(PREVIEW_HASH_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let shortened = app
+        .chars()
+        .take(max_length - hash.len() - 1)
+        .collect::<String>();
+
+    Ok(format!("{}-{}", shortened.trim_end_matches('-'), hash))
+}
+
+fn postgres_app_name(app: &str) -> String {
+    format!("{}-postgres", app)
+}
+
+/// The `--set` overrides that turn a config into the config of a preview app. Previews run a
+/// single instance in the default region and only get a new database when asked for one.
+pub fn preview_overrides(app: &str, database: bool) -> Vec<String> {
+    let mut overrides = vec![
+        format!("name={}", json!(app)),
+        "scaling.min_count=1".to_string(),
+        "scaling.max_count=1".to_string(),
+        "regions=[]".to_string(),
+        "backup_regions=[]".to_string(),
+    ];
+
+    if database {
+        overrides.push("database.postgres.cluster_size=1".to_string());
+    } else {
+        overrides.push("database=null".to_string());
+    }
+
+    overrides
+}
+
+/// The config file options for deploying the preview app of a branch.
+pub fn preview_config_file_options(
+    config_file_options: &ConfigFileOptions,
+    input_files: &[String],
+    branch: &str,
+    database: bool,
+) -> anyhow::Result<ConfigFileOptions> {
+    let deploy_config = DeployConfig::from_sources(&config_file_options.sources(input_files)?)?;
+    let app = preview_app_name(&deploy_config.name, branch)?;
+    let mut config_file_options = config_file_options.clone();
+
+    config_file_options
+        .overrides
+        .extend(preview_overrides(&app, database));
+
+    Ok(config_file_options)
+}
+
+/// Destroys the preview app and its Postgres app, returning the names of the destroyed apps.
+fn destroy_preview(client: &dyn FlyClient, app: &str) -> anyhow::Result<Vec<String>> {
+    let postgres_app = postgres_app_name(app);
+    let mut destroyed = vec![];
+
+    if client
+        .apps_list()?
+        .iter()
+        .any(|existing| existing.name == app)
+    {
+        client.apps_destroy(app)?;
+        destroyed.push(app.to_string());
+    }
+
+    if client
+        .postgres_list()?
+        .iter()
+        .any(|postgres| postgres.name == postgres_app)
+    {
+        client.apps_destroy(&postgres_app)?;
+        destroyed.push(postgres_app);
+    }
+
+    Ok(destroyed)
+}
+
+#[derive(Clone, Parser, Debug)]
+pub struct FlyPreviewDestroy {
+    /// The git branch of the preview app
+    pub branch: String,
+
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for FlyPreviewDestroy {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let deploy_config =
+            DeployConfig::from_sources(&self.config_file_options.sources(&self.input_files)?)?;
+        let app = preview_app_name(&deploy_config.name, &self.branch)?;
+        let destroyed = destroy_preview(&Flyctl, &app)?;
+
+        if destroyed.is_empty() {
+            println!("{}", format!("There is no preview app {}", app).yellow());
+        }
+
+        for app in destroyed {
+            println!("Destroyed {}", app.bold());
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+/// The preview apps of the config's app with the name of their database, when they have one.
+/// The database apps of previews are left out, but not previews of branches that end in
+/// `postgres`.
+pub fn preview_apps(
+    client: &dyn FlyClient,
+    name: &str,
+) -> anyhow::Result<Vec<(FlyctlApp, Option<String>)>> {
+    let prefix = preview_prefix(name);
+    let postgres = client.postgres_list()?;
+    let apps = client
+        .apps_list()?
+        .into_iter()
+        .filter(|app| app.name.starts_with(&prefix))
+        .collect::<Vec<_>>();
+    let databases = apps
+        .iter()
+        .map(|app| postgres_app_name(&app.name))
+        .filter(|postgres_app| {
+            postgres
+                .iter()
+                .any(|postgres| &postgres.name == postgres_app)
+        })
+        .collect::<Vec<_>>();
+
+    Ok(apps
+        .into_iter()
+        .filter(|app| !databases.contains(&app.name))
+        .map(|app| {
+            let postgres_app = postgres_app_name(&app.name);
+
+            if databases.contains(&postgres_app) {
+                (app, Some(postgres_app))
+            } else {
+                (app, None)
+            }
+        })
+        .collect())
+}
+
+#[derive(Clone, Parser, Debug)]
+pub struct FlyPreviewList {
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for FlyPreviewList {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let deploy_config =
+            DeployConfig::from_sources(&self.config_file_options.sources(&self.input_files)?)?;
+        for (app, database) in preview_apps(&Flyctl, &deploy_config.name)? {
+            println!(
+                "{} {} {}",
+                app.name.bold(),
+                app.status.as_deref().unwrap_or("unknown"),
+                database.as_deref().unwrap_or("-")
+            );
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+#[derive(Clone, Subcommand, Debug)]
+pub enum FlyPreviewSubcommand {
+    /// Destroys the preview app of a branch and its database
+    Destroy(FlyPreviewDestroy),
+    /// Lists the preview apps and their databases
+    List(FlyPreviewList),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{
+        config_sources::{ConfigSource, ConfigSources},
+        fly_client::RecordingFlyClient,
+    };
+
+    fn apps(names: &[&str]) -> Vec<FlyctlApp> {
+        names
+            .iter()
+            .map(|name| FlyctlApp {
+                name: name.to_string(),
+                ..FlyctlApp::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn derives_app_names_from_branches() {
+        assert_eq!(
+            preview_app_name("api", "Feature/Login_Page").unwrap(),
+            "api-preview-feature-login-page"
+        );
+        assert_eq!(
+            preview_app_name("api", &format!("fix-{}", "a".repeat(37))).unwrap(),
+            format!("api-preview-fix-{}", "a".repeat(37))
+        );
+
+        let long = preview_app_name("api", &format!("fix-{}-x", "a".repeat(37))).unwrap();
+        let other = preview_app_name("api", &format!("fix-{}-y", "a".repeat(37))).unwrap();
+
+        assert_eq!(long.len(), 63 - "-postgres".len());
+        assert!(long.starts_with(&format!("api-preview-fix-{}-", "a".repeat(31))));
+        assert_ne!(long, other);
+        assert!(preview_app_name("api", "///").is_err());
+    }
+
+    #[test]
+    fn only_previews_get_a_single_node_database() {
+        let cluster_size = |overrides: &[String]| {
+            let mut sources = ConfigSources {
+                sources: vec![ConfigSource::parse(
+                    "fly.json",
+                    r#"{ "name": "api", "organization": "personal", "default_region": "ord", "database": { "postgres": {} } }"#,
+                )
+                .unwrap()],
+            };
+            sources.set(overrides).unwrap();
+
+            DeployConfig::from_sources(&sources)
+                .unwrap()
+                .database
+                .and_then(|database| database.postgres)
+                .map(|postgres| postgres.cluster_size)
+        };
+
+        assert_eq!(cluster_size(&[]), Some(2));
+        assert_eq!(
+            cluster_size(&preview_overrides("api-preview-login", true)),
+            Some(1)
+        );
+        assert_eq!(
+            cluster_size(&preview_overrides("api-preview-login", false)),
+            None
+        );
+    }
+
+    #[test]
+    fn lists_previews_without_their_databases() {
+        let client = RecordingFlyClient {
+            apps: apps(&[
+                "api",
+                "api-preview-login",
+                "api-preview-login-postgres",
+                "api-preview-migrate-postgres",
+            ]),
+            postgres: apps(&["api-postgres", "api-preview-login-postgres"]),
+            ..RecordingFlyClient::default()
+        };
+
+        assert_eq!(
+            preview_apps(&client, "api")
+                .unwrap()
+                .into_iter()
+                .map(|(app, database)| (app.name, database))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "api-preview-login".to_string(),
+                    Some("api-preview-login-postgres".to_string())
+                ),
+                ("api-preview-migrate-postgres".to_string(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn destroys_the_app_and_its_database() {
+        let client = RecordingFlyClient {
+            apps: apps(&["api", "api-preview-login", "api-preview-login-postgres"]),
+            postgres: apps(&["api-postgres", "api-preview-login-postgres"]),
+            ..RecordingFlyClient::default()
+        };
+
+        assert_eq!(
+            destroy_preview(&client, "api-preview-login").unwrap(),
+            vec!["api-preview-login", "api-preview-login-postgres"]
+        );
+        assert_eq!(
+            client.calls(),
+            vec![
+                "apps list",
+                "apps destroy api-preview-login",
+                "postgres list",
+                "apps destroy api-preview-login-postgres",
+            ]
+        );
+    }
+}
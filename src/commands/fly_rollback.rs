@@ -0,0 +1,225 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use clap::Parser;
+use colored::*;
+
+use crate::{
+    models::{fly_models::DeployConfig, flyctl_models::FlyctlRelease},
+    utils::fly_client::{FlyClient, Flyctl},
+};
+
+use super::{
+    failed_step, ConfigFileOptions, FlyConfigGenOptions, FlyDeployPlan, FlyDeployStep,
+    SecretOptions,
+};
+
+/// The newest release that finished deploying and has an image to go back to.
+pub fn latest_stable_release(releases: &[FlyctlRelease]) -> Option<FlyctlRelease> {
+    releases
+        .iter()
+        .filter(|release| release.stable && release.image_ref.is_some())
+        .max_by_key(|release| release.version)
+        .cloned()
+}
+
+/// The release to roll back to: the chosen version, or the newest stable release before the
+/// current one that runs a different image.
+pub fn rollback_target(
+    releases: &[FlyctlRelease],
+    to: Option<u64>,
+) -> anyhow::Result<FlyctlRelease> {
+    if let Some(version) = to {
+        return match releases.iter().find(|release| release.version == version) {
+            Some(release) if release.image_ref.is_some() => Ok(release.clone()),
+            Some(_) => anyhow::bail!("Release v{} has no image to roll back to", version),
+            None => anyhow::bail!("Release v{} does not exist", version),
+        };
+    }
+
+    let current = match releases.iter().max_by_key(|release| release.version) {
+        Some(current) => current,
+        None => anyhow::bail!("The app has no releases"),
+    };
+
+    let previous = releases.iter().filter(|release| {
+        release.version < current.version && release.image_ref != current.image_ref
+    });
+
+    match latest_stable_release(&previous.cloned().collect::<Vec<_>>()) {
+        Some(release) => Ok(release),
+        None => anyhow::bail!(
+            "There is no stable release before v{} to roll back to",
+            current.version
+        ),
+    }
+}
+
+/// Redeploys the image of the release and reconciles regions and scaling.
+pub fn rollback(
+    client: &dyn FlyClient,
+    deploy_config: &DeployConfig,
+    release: &FlyctlRelease,
+) -> anyhow::Result<()> {
+    let image = release.image_ref.as_deref().unwrap_or_default();
+
+    println!(
+        "Rolling back {} to v{} ({})",
+        deploy_config.name.bold(),
+        release.version,
+        image
+    );
+
+    FlyDeployPlan::rollback(deploy_config, image).apply(client, &BTreeMap::new())
+}
+
+/// Whether the error is from the deploy step or a step after it, meaning a new release may
+/// have been created.
+pub fn failed_after_deploy(plan: &FlyDeployPlan, error: &anyhow::Error) -> bool {
+    let failed_step = failed_step(error);
+    let deploy_index = plan
+        .steps
+        .iter()
+        .position(|step| matches!(step, FlyDeployStep::Deploy { .. }));
+
+    match (failed_step, deploy_index) {
+        (Some(failed_step), Some(deploy_index)) => plan.steps[deploy_index..]
+            .iter()
+            .any(|step| step.name() == failed_step),
+        _ => false,
+    }
+}
+
+#[derive(Clone, Parser, Debug)]
+pub struct FlyRollback {
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    /// The release version to roll back to, defaults to the previous stable release
+    #[clap(long)]
+    pub to: Option<u64>,
+
+    /// Print the flyctl commands that would be run and exit without changing anything
+    #[clap(long)]
+    pub plan: bool,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+
+    #[clap(flatten)]
+    pub secret_options: SecretOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for FlyRollback {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let deploy_config =
+            DeployConfig::from_sources(&self.config_file_options.sources(&self.input_files)?)?;
+        let client = Flyctl;
+        let releases = client.releases(&deploy_config.name)?;
+        let release = rollback_target(&releases, self.to)?;
+
+        if self.plan {
+            print!(
+                "{}",
+                FlyDeployPlan::rollback(
+                    &deploy_config,
+                    release.image_ref.as_deref().unwrap_or_default()
+                )
+            );
+
+            return anyhow::Ok(());
+        }
+
+        let fly_config_gen = FlyConfigGenOptions {
+            output_file: "fly.toml".to_string(),
+            input_files: self.input_files.clone(),
+            redact: false,
+            merged_file: None,
+            allow_tracked: false,
+            config_file_options: self.config_file_options.clone(),
+            secret_options: self.secret_options.clone(),
+        };
+
+        fly_config_gen.execute().await?;
+
+        rollback(&client, &deploy_config, &release)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{errors::LsctlError, utils::fly_client::RecordingFlyClient};
+
+    fn release(version: u64, stable: bool, image: &str) -> FlyctlRelease {
+        FlyctlRelease {
+            version,
+            stable,
+            image_ref: Some(image.to_string()),
+            ..FlyctlRelease::default()
+        }
+    }
+
+    fn releases() -> Vec<FlyctlRelease> {
+        vec![
+            release(5, true, "registry.fly.io/api:5"),
+            release(4, false, "registry.fly.io/api:4"),
+            release(3, true, "registry.fly.io/api:5"),
+            release(2, true, "registry.fly.io/api:2"),
+            release(1, true, "registry.fly.io/api:1"),
+        ]
+    }
+
+    #[test]
+    fn chooses_the_previous_stable_release_with_another_image() {
+        assert_eq!(rollback_target(&releases(), None).unwrap().version, 2);
+        assert_eq!(rollback_target(&releases(), Some(1)).unwrap().version, 1);
+        assert!(rollback_target(&releases(), Some(9)).is_err());
+        assert!(rollback_target(&releases()[..1], None).is_err());
+    }
+
+    #[test]
+    fn redeploys_the_image_and_reconciles_scaling() {
+        let deploy_config: DeployConfig = serde_json::from_value(json!({
+            "name": "api",
+            "organization": "personal",
+            "default_region": "ord",
+            "regions": ["iad"],
+            "scaling": { "memory": 512, "min_count": 2, "balance_method": "static" },
+            "database": { "postgres": { "vm_size": "shared-cpu-1x" } },
+            "environment": [{ "key": "SECRET_KEY", "value": "secret", "secret": true }],
+            "hooks": { "pre_deploy": "npm run migrate" }
+        }))
+        .unwrap();
+        let client = RecordingFlyClient::default();
+
+        rollback(&client, &deploy_config, &releases()[3]).unwrap();
+
+        assert_eq!(
+            client.calls(),
+            vec![
+                "deploy ord registry.fly.io/api:2",
+                "scale count api 2",
+                "scale memory api 512",
+                "regions set api iad,ord",
+                "regions backup api ",
+            ]
+        );
+
+        let plan = FlyDeployPlan::rollback(&deploy_config, "registry.fly.io/api:2");
+        let failed = |step: &str| {
+            anyhow::Error::new(LsctlError::Flyctl {
+                step: step.to_string(),
+                exit_code: None,
+                source: anyhow::anyhow!("failed"),
+            })
+        };
+
+        assert!(failed_after_deploy(&plan, &failed("scale-memory")));
+        assert!(!failed_after_deploy(&plan, &failed("launch")));
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use super::{FlyDeployPlan, FlyDeployStep};
+use crate::utils::{file_utils, fly_client::FlyDeployArgs};
+
+/// Where `lsctl fly deploy` keeps the state of unfinished deploys, relative to the current
+/// directory.
+pub static DEPLOY_STATE_DIR: &str = ".lsctl/deploys";
+
+/// The steps of an unfinished deploy of an app that completed, in the order they ran. Hooks of
+/// the same phase share a name, so a name is listed once for every step that completed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct FlyDeployState {
+    pub app: String,
+    /// Identifies the config and image that were deployed, see `FlyDeployState::fingerprint`
+    pub fingerprint: String,
+    pub completed_steps: Vec<String>,
+    /// Where the state is saved, `None` when it is not
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl FlyDeployState {
+    /// Reads the state of the last deploy of the app when resuming, or starts a new one. A deploy
+    /// can only be resumed with the config and image it was started with.
+    pub fn open(
+        dir: &Path,
+        app: &str,
+        fingerprint: &str,
+        resume: bool,
+    ) -> anyhow::Result<FlyDeployState> {
+        let path = dir.join(format!("{}.json", app));
+
+        if resume && path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let state: FlyDeployState = serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+            if state.fingerprint != fingerprint {
+                anyhow::bail!(
+                    "The config or image of {} changed since the last deploy, deploy it again without --resume",
+                    app
+                );
+            }
+
+            return Ok(FlyDeployState {
+                path: Some(path),
+                ..state
+            });
+        }
+
+        Ok(FlyDeployState {
+            app: app.to_string(),
+            fingerprint: fingerprint.to_string(),
+            completed_steps: vec![],
+            path: Some(path),
+        })
+    }
+
+    /// Hashes the rendered config together with the image that is deployed.
+    pub fn fingerprint(config: &Value, deploy_args: &FlyDeployArgs) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(config.to_string().as_bytes());
+        hasher.update([0]);
+        hasher.update(deploy_args.image.as_deref().unwrap_or_default().as_bytes());
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    /// Stops saving the state, so runs of only some of the steps leave the saved state as it is.
+    pub fn detach(&mut self) {
+        self.path = None;
+    }
+
+    pub fn is_saved(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Removes the steps that completed in the last deploy from the plan and returns them.
+    pub fn skip_completed(&self, plan: &mut FlyDeployPlan) -> Vec<FlyDeployStep> {
+        let mut completed = HashMap::new();
+
+        for name in &self.completed_steps {
+            *completed.entry(name.to_string()).or_insert(0) += 1;
+        }
+
+        let (skipped, steps) =
+            plan.steps
+                .drain(..)
+                .partition(|step| match completed.get_mut(&step.name()) {
+                    Some(count) if *count > 0 => {
+                        *count -= 1;
+                        true
+                    }
+                    _ => false,
+                });
+
+        plan.steps = steps;
+        skipped
+    }
+
+    /// Records that the step completed and saves the state.
+    pub fn complete(&mut self, step: &FlyDeployStep) -> anyhow::Result<()> {
+        self.completed_steps.push(step.name());
+        self.save()
+    }
+
+    /// Writes the state to the file of the app, unless it is detached.
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = match &self.path {
+            Some(path) => path.to_str().context("Invalid deploy state path")?,
+            None => return Ok(()),
+        };
+
+        file_utils::create_and_write_file(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write {}", path))
+    }
+
+    /// Deletes the saved state once the deploy finished, unless it is detached.
+    pub fn remove(&self) -> anyhow::Result<()> {
+        match &self.path {
+            Some(path) if path.exists() => fs::remove_file(path)
+                .with_context(|| format!("Failed to remove {}", path.display())),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{commands::FlyAppState, models::fly_models::DeployConfig};
+
+    #[test]
+    fn resumes_after_the_completed_steps() {
+        let dir = std::env::temp_dir().join(format!("lsctl-deploy-state-{}", std::process::id()));
+        let deploy_config: DeployConfig = serde_json::from_value(json!({
+            "name": "api",
+            "organization": "personal",
+            "default_region": "ord",
+            "hooks": { "pre_deploy": ["true", "true"] }
+        }))
+        .unwrap();
+        let plan = || {
+            FlyDeployPlan::new(
+                &deploy_config,
+                &FlyAppState::default(),
+                &FlyDeployArgs::default(),
+            )
+        };
+
+        let open = |resume| FlyDeployState::open(&dir, "api", "config", resume);
+        let mut state = open(false).unwrap();
+
+        for step in &plan().steps[..3] {
+            state.complete(step).unwrap();
+        }
+
+        let state = open(true).unwrap();
+        let mut resumed = plan();
+
+        assert_eq!(
+            state.completed_steps,
+            vec!["launch", "pre-deploy", "pre-deploy"]
+        );
+        assert_eq!(state.skip_completed(&mut resumed).len(), 3);
+        assert_eq!(resumed.steps, plan().steps[3..]);
+        assert!(open(false).unwrap().completed_steps.is_empty());
+
+        let mut partial = open(false).unwrap();
+        partial.detach();
+        partial.complete(&plan().steps[3]).unwrap();
+        partial.remove().unwrap();
+
+        assert_eq!(open(true).unwrap().completed_steps.len(), 3);
+        assert_eq!(
+            FlyDeployState::open(&dir, "api", "changed config", true)
+                .unwrap_err()
+                .to_string(),
+            "The config or image of api changed since the last deploy, deploy it again without --resume"
+        );
+
+        state.remove().unwrap();
+
+        assert!(open(true).unwrap().completed_steps.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fingerprints_the_config_and_image() {
+        let image = |image: &str| FlyDeployArgs {
+            image: Some(image.to_string()),
+            ..FlyDeployArgs::default()
+        };
+        let fingerprint = FlyDeployState::fingerprint(&json!({ "name": "api" }), &image("api:1"));
+
+        assert_eq!(
+            fingerprint,
+            FlyDeployState::fingerprint(&json!({ "name": "api" }), &image("api:1"))
+        );
+        assert_ne!(
+            fingerprint,
+            FlyDeployState::fingerprint(&json!({ "name": "api" }), &image("api:2"))
+        );
+        assert_ne!(
+            fingerprint,
+            FlyDeployState::fingerprint(&json!({ "name": "web" }), &image("api:1"))
+        );
+    }
+}
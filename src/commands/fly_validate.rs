@@ -0,0 +1,241 @@
+use std::{collections::HashMap, fmt};
+
+use crate::models::fly_models::*;
+
+/// The codes of the regions Fly apps can run in.
+pub static FLY_REGIONS: &[&str] = &[
+    "ams", "arn", "atl", "bog", "bom", "bos", "cdg", "den", "dfw", "ewr", "eze", "fra", "gdl",
+    "gig", "gru", "hkg", "iad", "jnb", "lax", "lhr", "maa", "mad", "mia", "nrt", "ord", "otp",
+    "phx", "qro", "scl", "sea", "sin", "sjc", "syd", "waw", "yul", "yyz",
+];
+
+/// The longest `kill_timeout` Fly accepts, in seconds.
+pub static MAX_KILL_TIMEOUT: u64 = 300;
+
+/// A semantic problem with a config, at a JSON pointer into the config.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub pointer: String,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    fn new(pointer: String, message: String) -> ConfigProblem {
+        ConfigProblem { pointer, message }
+    }
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.pointer, self.message)
+    }
+}
+
+/// Checks the config for problems serde cannot catch and returns all of them.
+pub fn validate(deploy_config: &DeployConfig) -> Vec<ConfigProblem> {
+    let mut problems = vec![];
+    let scaling = &deploy_config.scaling;
+
+    if scaling.min_count > scaling.max_count {
+        problems.push(ConfigProblem::new(
+            "/scaling/min_count".to_string(),
+            format!(
+                "min_count {} is greater than max_count {}",
+                scaling.min_count, scaling.max_count
+            ),
+        ));
+    }
+
+    let regions = std::iter::once(("/default_region".to_string(), &deploy_config.default_region))
+        .chain(
+            deploy_config
+                .regions
+                .iter()
+                .enumerate()
+                .map(|(index, region)| (format!("/regions/{}", index), region)),
+        )
+        .chain(
+            deploy_config
+                .backup_regions
+                .iter()
+                .enumerate()
+                .map(|(index, region)| (format!("/backup_regions/{}", index), region)),
+        );
+
+    for (pointer, region) in regions {
+        if !FLY_REGIONS.contains(&region.as_str()) {
+            problems.push(ConfigProblem::new(
+                pointer,
+                format!("{} is not a known Fly region", region),
+            ));
+        }
+    }
+
+    for (index, region) in deploy_config.backup_regions.iter().enumerate() {
+        if region == &deploy_config.default_region {
+            problems.push(ConfigProblem::new(
+                format!("/backup_regions/{}", index),
+                format!(
+                    "{} is the default_region and cannot be a backup region",
+                    region
+                ),
+            ));
+        }
+    }
+
+    let mut ports: HashMap<u64, String> = HashMap::new();
+
+    for (service_index, service) in deploy_config.services.iter().flatten().enumerate() {
+        for (port_index, port) in service.ports.iter().enumerate() {
+            let pointer = format!("/services/{}/ports/{}", service_index, port_index);
+
+            match ports.get(&port.port) {
+                Some(other) => problems.push(ConfigProblem::new(
+                    format!("{}/port", pointer),
+                    format!("port {} is already used by {}", port.port, other),
+                )),
+                None => {
+                    ports.insert(port.port, pointer.to_string());
+                }
+            }
+
+            if port.force_https == Some(true)
+                && !port.handlers.contains(&FlyServicePortHandler::Http)
+            {
+                problems.push(ConfigProblem::new(
+                    format!("{}/force_https", pointer),
+                    "force_https can only be enabled on ports with the http handler".to_string(),
+                ));
+            }
+        }
+    }
+
+    for (index, env_var) in deploy_config.environment.iter().flatten().enumerate() {
+        let config = match &env_var.value {
+            EnvironmentVariableValue::FromGcpKms { .. } if deploy_config.gcp_kms.is_none() => {
+                "gcp_kms"
+            }
+            EnvironmentVariableValue::FromGcpSsm { .. } if deploy_config.gcp_ssm.is_none() => {
+                "gcp_ssm"
+            }
+            EnvironmentVariableValue::FromVault { .. } if deploy_config.vault.is_none() => "vault",
+            _ => continue,
+        };
+
+        problems.push(ConfigProblem::new(
+            format!("/environment/{}/{}", index, env_var.value.source()),
+            format!(
+                "{} is {} but {} is not set",
+                env_var.key,
+                env_var.value.source(),
+                config
+            ),
+        ));
+    }
+
+    if let Some(kill_timeout) = deploy_config.kill_timeout {
+        if kill_timeout == 0 || kill_timeout > MAX_KILL_TIMEOUT {
+            problems.push(ConfigProblem::new(
+                "/kill_timeout".to_string(),
+                format!(
+                    "kill_timeout {} must be between 1 and {} seconds",
+                    kill_timeout, MAX_KILL_TIMEOUT
+                ),
+            ));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn deploy_config(config: Value) -> DeployConfig {
+        let mut merged = json!({
+            "name": "api",
+            "organization": "personal",
+            "default_region": "ord",
+            "regions": ["ord", "iad"],
+            "backup_regions": ["sea"],
+            "scaling": { "min_count": 1, "max_count": 3 },
+            "kill_timeout": 5,
+            "services": [{
+                "internal_port": 3000,
+                "processes": ["app"],
+                "concurrency": { "type": "connections" },
+                "ports": [
+                    { "port": 80, "handlers": ["http"] },
+                    { "port": 443, "handlers": ["tls", "http"], "force_https": true }
+                ]
+            }]
+        });
+        json_patch::merge(&mut merged, &config);
+
+        serde_json::from_value(merged).unwrap()
+    }
+
+    fn pointers(problems: &[ConfigProblem]) -> Vec<&str> {
+        problems
+            .iter()
+            .map(|problem| problem.pointer.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn accepts_a_valid_config() {
+        assert_eq!(validate(&deploy_config(json!({}))), vec![]);
+    }
+
+    #[test]
+    fn accepts_disabled_force_https_without_the_http_handler() {
+        let deploy_config = deploy_config(json!({
+            "services": [{
+                "internal_port": 3000,
+                "processes": ["app"],
+                "concurrency": { "type": "connections" },
+                "ports": [{ "port": 5432, "handlers": ["tls"], "force_https": false }]
+            }]
+        }));
+
+        assert_eq!(validate(&deploy_config), vec![]);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let deploy_config = deploy_config(json!({
+            "regions": ["ord", "xyz"],
+            "backup_regions": ["ord"],
+            "scaling": { "min_count": 4 },
+            "kill_timeout": 600,
+            "services": [{
+                "internal_port": 3000,
+                "processes": ["app"],
+                "concurrency": { "type": "connections" },
+                "ports": [
+                    { "port": 80, "handlers": ["http"] },
+                    { "port": 80, "handlers": ["tls"], "force_https": true }
+                ]
+            }],
+            "environment": [
+                { "key": "A", "value": "a" },
+                { "key": "B", "from_gcp_kms": { "value": "Yg==" } }
+            ]
+        }));
+
+        assert_eq!(
+            pointers(&validate(&deploy_config)),
+            vec![
+                "/scaling/min_count",
+                "/regions/1",
+                "/backup_regions/0",
+                "/services/0/ports/1/port",
+                "/services/0/ports/1/force_https",
+                "/environment/1/from_gcp_kms",
+                "/kill_timeout",
+            ]
+        );
+    }
+}
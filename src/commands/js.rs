@@ -3,7 +3,7 @@ use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, fs, process::Command};
 
-use crate::utils::file_utils;
+use crate::{errors::LsctlError, utils::file_utils};
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize)]
 #[serde(rename_all(deserialize = "camelCase", serialize = "camelCase"))]
@@ -19,14 +19,13 @@ fn has_key(map: &Option<HashMap<String, String>>, key: &str) -> bool {
 }
 
 impl PackageJson {
-    fn new() -> anyhow::Result<Self> {
-        match fs::read_to_string("./package.json") {
-            Ok(contents) => match serde_json::from_str(&contents) {
-                Ok(package_json) => Ok(package_json),
-                Err(e) => anyhow::bail!("Failed to parse package.json: {}", e),
-            },
-            Err(e) => anyhow::bail!("Failed to read package.json: {}", e),
-        }
+    fn new(file: &str) -> Result<Self, LsctlError> {
+        let contents = fs::read_to_string(file).map_err(|e| LsctlError::ConfigIo {
+            file: file.to_string(),
+            source: e,
+        })?;
+
+        serde_json::from_str(&contents).map_err(|e| LsctlError::config_parse(file, &e))
     }
 
     fn has_dependency(&self, name: &str) -> bool {
@@ -48,7 +47,7 @@ pub struct JsConfigOptions {
 #[async_trait]
 impl super::CommandRunner for JsConfigOptions {
     async fn execute(&self) -> anyhow::Result<()> {
-        let package_json = PackageJson::new().unwrap();
+        let package_json = PackageJson::new("package.json")?;
 
         let is_esm = package_json.is_esm();
         let is_typescript = package_json.has_dependency("typescript");
@@ -104,8 +103,7 @@ impl super::CommandRunner for JsConfigOptions {
                 .arg("install")
                 .arg("-D")
                 .args(dependencies)
-                .output()
-                .unwrap();
+                .output()?;
         }
 
         println!("Creating a SWC config");
@@ -141,7 +139,7 @@ impl super::CommandRunner for JsConfigOptions {
             syntax, target, the_type
         );
 
-        file_utils::create_and_write_file("./.swcrc", swc_config).unwrap();
+        file_utils::create_and_write_file("./.swcrc", swc_config)?;
 
         if is_typescript {
             println!("Creating a TSConfig");
@@ -154,7 +152,7 @@ impl super::CommandRunner for JsConfigOptions {
                 if is_esm { "esm" } else { "cjs" }
             );
 
-            file_utils::create_and_write_file("./tsconfig.json", tsconfig).unwrap();
+            file_utils::create_and_write_file("./tsconfig.json", tsconfig)?;
         }
 
         println!("Creating a ESLint config");
@@ -169,8 +167,7 @@ impl super::CommandRunner for JsConfigOptions {
         file_utils::create_and_write_file(
             format!("./.eslintrc.{}", js_file_ext).as_str(),
             eslint_config,
-        )
-        .unwrap();
+        )?;
 
         println!("Creating a Prettier config");
 
@@ -185,8 +182,7 @@ impl super::CommandRunner for JsConfigOptions {
         file_utils::create_and_write_file(
             format!("./.prettierrc.{}", js_file_ext).as_str(),
             eslint_config,
-        )
-        .unwrap();
+        )?;
 
         anyhow::Ok(())
     }
@@ -197,3 +193,30 @@ pub enum JsSubcommand {
     /// Creates the recommended swc config and tsconfig file based on the package.json
     Config(JsConfigOptions),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors;
+
+    #[test]
+    fn fails_with_config_errors_for_the_package_json() {
+        let dir = std::env::temp_dir().join(format!("lsctl-js-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let file = dir.join("package.json").display().to_string();
+        let missing = PackageJson::new(&file).unwrap_err();
+
+        fs::write(&file, "{\n  \"type\": module\n}").unwrap();
+        let malformed = PackageJson::new(&file).unwrap_err();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(errors::exit_code(&missing.into()), 3);
+        assert_eq!(
+            malformed.to_string(),
+            format!("Failed to parse {}:2:11: expected value", file)
+        );
+        assert_eq!(errors::exit_code(&malformed.into()), 4);
+    }
+}
@@ -3,11 +3,29 @@ use clap::{Parser, Subcommand};
 
 mod fly;
 mod fly_config;
+mod fly_diff;
+mod fly_health;
+mod fly_import;
+mod fly_plan;
+mod fly_preview;
+mod fly_rollback;
+mod fly_state;
+mod fly_validate;
 mod js;
+mod secrets;
 
 pub use fly::*;
 pub use fly_config::*;
+pub use fly_diff::*;
+pub use fly_health::*;
+pub use fly_import::*;
+pub use fly_plan::*;
+pub use fly_preview::*;
+pub use fly_rollback::*;
+pub use fly_state::*;
+pub use fly_validate::*;
 pub use js::*;
+pub use secrets::*;
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
@@ -18,6 +36,10 @@ pub enum Command {
     /// Used for js related things
     #[clap(subcommand)]
     Js(JsSubcommand),
+
+    /// Used for encrypting secrets
+    #[clap(subcommand)]
+    Secrets(SecretsSubcommand),
 }
 
 #[derive(Parser, Debug)]
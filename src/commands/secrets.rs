@@ -0,0 +1,342 @@
+use std::io::Read;
+
+use anyhow::Context;
+use async_trait::async_trait;
+use clap::{Parser, Subcommand};
+use colored::*;
+use serde_json::{json, Value};
+
+use super::ConfigFileOptions;
+use crate::{
+    models::fly_models::{DeployConfig, FlyGcpKms},
+    utils::{config_sources::ConfigFormat, file_utils, gcp_auth::GcpClient, gcp_kms},
+};
+
+#[derive(Clone, Parser, Debug)]
+pub struct SecretsEncryptOptions {
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    /// The key of the environment variable the value is for
+    #[clap(long)]
+    pub key: String,
+
+    /// Write the encrypted value into the environment of this input file instead of printing it
+    #[clap(long)]
+    pub file: Option<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for SecretsEncryptOptions {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let deploy_config =
+            DeployConfig::from_sources(&self.config_file_options.sources(&self.input_files)?)?;
+        let kms = KmsKey::new(&deploy_config)?;
+        let plaintext = read_stdin()?;
+        let ciphertext = kms.encrypt(&plaintext).await?;
+
+        let file = match &self.file {
+            Some(file) => file,
+            None => {
+                println!("{}", ciphertext);
+
+                return anyhow::Ok(());
+            }
+        };
+
+        let mut config = read_config_file(file)?;
+        set_gcp_kms_value(&mut config, &self.key, &ciphertext)?;
+        write_config_file(file, &config)?;
+
+        println!("Encrypted {} into {}", self.key.bold(), file);
+
+        anyhow::Ok(())
+    }
+}
+
+#[derive(Clone, Parser, Debug)]
+pub struct SecretsRotateOptions {
+    /// The names of the input JSON config files
+    #[clap(default_value = "fly.json")]
+    pub input_files: Vec<String>,
+
+    /// Only re-encrypt the environment variable with this key
+    #[clap(long)]
+    pub key: Option<String>,
+
+    /// Only re-encrypt the values in this input file
+    #[clap(long)]
+    pub file: Option<String>,
+
+    #[clap(flatten)]
+    pub config_file_options: ConfigFileOptions,
+}
+
+#[async_trait]
+impl super::CommandRunner for SecretsRotateOptions {
+    async fn execute(&self) -> anyhow::Result<()> {
+        let sources = self.config_file_options.sources(&self.input_files)?;
+        let deploy_config = DeployConfig::from_sources(&sources)?;
+        let kms = KmsKey::new(&deploy_config)?;
+
+        // Every file the config is read from, including the ones from --env and extends
+        let files = match &self.file {
+            Some(file) => vec![file.to_string()],
+            None => sources.file_paths(),
+        };
+
+        println!("Re-encrypting from_gcp_kms values with the primary key version:");
+
+        for file in &files {
+            let mut config = read_config_file(file)?;
+            let mut rotated = 0;
+
+            for entry in config
+                .get_mut("environment")
+                .and_then(Value::as_array_mut)
+                .into_iter()
+                .flatten()
+            {
+                let key = entry
+                    .get("key")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+
+                if matches!(&self.key, Some(only) if *only != key) {
+                    continue;
+                }
+
+                let ciphertext = match entry.pointer_mut("/from_gcp_kms/value") {
+                    Some(Value::String(ciphertext)) => ciphertext,
+                    _ => continue,
+                };
+
+                let plaintext = kms
+                    .decrypt(ciphertext)
+                    .await
+                    .with_context(|| format!("Failed to decrypt {} in {}", key, file))?;
+
+                *ciphertext = kms.encrypt(&plaintext).await?;
+                rotated += 1;
+            }
+
+            if rotated > 0 {
+                write_config_file(file, &config)?;
+            }
+
+            println!("    {} {} value(s)", file.bold(), rotated);
+        }
+
+        anyhow::Ok(())
+    }
+}
+
+/// The Cloud KMS key from the `gcp_kms` settings of a config.
+struct KmsKey {
+    client: GcpClient,
+    config: FlyGcpKms,
+}
+
+impl KmsKey {
+    fn new(deploy_config: &DeployConfig) -> anyhow::Result<KmsKey> {
+        Ok(KmsKey {
+            client: GcpClient::new(None),
+            config: deploy_config
+                .gcp_kms
+                .clone()
+                .context("gcp_kms config is not set")?,
+        })
+    }
+
+    fn endpoint(&self) -> &str {
+        self.config
+            .endpoint
+            .as_deref()
+            .unwrap_or(gcp_kms::DEFAULT_ENDPOINT)
+    }
+
+    async fn encrypt(&self, plaintext: &str) -> anyhow::Result<String> {
+        gcp_kms::encrypt_plaintext(
+            &self.client,
+            self.endpoint(),
+            &self.config.project,
+            &self.config.location,
+            &self.config.key_ring,
+            &self.config.key,
+            plaintext,
+        )
+        .await
+    }
+
+    async fn decrypt(&self, ciphertext: &str) -> anyhow::Result<String> {
+        gcp_kms::decrypt_ciphertext(
+            &self.client,
+            self.endpoint(),
+            &self.config.project,
+            &self.config.location,
+            &self.config.key_ring,
+            &self.config.key,
+            ciphertext,
+        )
+        .await
+    }
+}
+
+/// Reads the value to encrypt from stdin without the trailing newline added by `echo`.
+fn read_stdin() -> anyhow::Result<String> {
+    let mut value = String::new();
+    std::io::stdin()
+        .read_to_string(&mut value)
+        .context("Failed to read the value from stdin")?;
+
+    let value = value
+        .strip_suffix('\n')
+        .map(|value| value.strip_suffix('\r').unwrap_or(value))
+        .unwrap_or(&value);
+
+    if value.is_empty() {
+        anyhow::bail!("No value was given on stdin");
+    }
+
+    Ok(value.to_string())
+}
+
+fn read_config_file(file: &str) -> anyhow::Result<Value> {
+    let contents =
+        std::fs::read_to_string(file).with_context(|| format!("Failed to read file {}", file))?;
+
+    Ok(ConfigFormat::of(file).parse(file, &contents)?)
+}
+
+/// Writes the config in the format of the file's extension.
+fn write_config_file(file: &str, config: &Value) -> anyhow::Result<()> {
+    let mut contents = ConfigFormat::of(file).serialize(config)?;
+
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+
+    match file_utils::create_and_write_file(file, contents) {
+        Ok(_) => Ok(()),
+        Err(e) => anyhow::bail!("Error writing file {}: {}", file, e),
+    }
+}
+
+/// Sets the environment variable to the ciphertext, replacing whatever value it had or adding it
+/// to the end of `environment`. The variable is always marked as a secret, so the decrypted value
+/// is never written into fly.toml.
+fn set_gcp_kms_value(config: &mut Value, key: &str, ciphertext: &str) -> anyhow::Result<()> {
+    let object = config
+        .as_object_mut()
+        .context("The config file is not a JSON object")?;
+    let environment = object
+        .entry("environment")
+        .or_insert_with(|| json!([]))
+        .as_array_mut()
+        .context("environment is not an array")?;
+
+    let value = json!({ "value": ciphertext });
+
+    match environment
+        .iter_mut()
+        .filter_map(Value::as_object_mut)
+        .find(|entry| entry.get("key").and_then(Value::as_str) == Some(key))
+    {
+        Some(entry) => {
+            entry.retain(|name, _| name == "key");
+            entry.insert("from_gcp_kms".to_string(), value);
+            entry.insert("secret".to_string(), json!(true));
+        }
+        None => environment.push(json!({ "key": key, "from_gcp_kms": value, "secret": true })),
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Subcommand, Debug)]
+pub enum SecretsSubcommand {
+    /// Encrypts a value from stdin with the gcp_kms key of the config
+    Encrypt(SecretsEncryptOptions),
+    /// Re-encrypts the from_gcp_kms values of the config with the primary key version
+    Rotate(SecretsRotateOptions),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_the_value_of_an_existing_variable() {
+        let mut config = json!({
+            "environment": [
+                { "key": "A", "value": "plain", "secret": true },
+                { "key": "B", "value": "b" }
+            ]
+        });
+
+        set_gcp_kms_value(&mut config, "A", "Y2lwaGVy").unwrap();
+        set_gcp_kms_value(&mut config, "B", "Yg==").unwrap();
+        set_gcp_kms_value(&mut config, "C", "Yw==").unwrap();
+
+        assert_eq!(
+            config,
+            json!({
+                "environment": [
+                    { "key": "A", "from_gcp_kms": { "value": "Y2lwaGVy" }, "secret": true },
+                    { "key": "B", "from_gcp_kms": { "value": "Yg==" }, "secret": true },
+                    { "key": "C", "from_gcp_kms": { "value": "Yw==" }, "secret": true }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_the_format_of_the_config_file() {
+        let dir = std::env::temp_dir().join(format!("lsctl-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        for (name, contents) in [
+            (
+                "fly.yaml",
+                "name: api\nenvironment:\n  - key: A\n    value: a\n",
+            ),
+            (
+                "fly.toml",
+                "name = \"api\"\n\n[[environment]]\nkey = \"A\"\nvalue = \"a\"\n",
+            ),
+            (
+                "fly.json5",
+                "{ name: 'api', environment: [{ key: 'A', value: 'a' }] }",
+            ),
+        ] {
+            let file = dir.join(name).display().to_string();
+            std::fs::write(&file, contents).unwrap();
+
+            let mut config = read_config_file(&file).unwrap();
+            set_gcp_kms_value(&mut config, "B", "Yg==").unwrap();
+            write_config_file(&file, &config).unwrap();
+
+            let written = std::fs::read_to_string(&file).unwrap();
+
+            assert_eq!(
+                ConfigFormat::of(&file).parse(&file, &written).unwrap(),
+                json!({
+                    "name": "api",
+                    "environment": [
+                        { "key": "A", "value": "a" },
+                        { "key": "B", "from_gcp_kms": { "value": "Yg==" }, "secret": true }
+                    ]
+                }),
+                "{}",
+                name
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+
+use thiserror::Error;
+
+/// The errors lsctl reports with their own process exit code so scripts can tell failures apart.
+#[derive(Debug, Error)]
+pub enum LsctlError {
+    #[error("Failed to read {file}")]
+    ConfigIo {
+        file: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse {location}: {message}")]
+    ConfigParse {
+        location: ConfigLocation,
+        message: String,
+    },
+
+    /// A command exited unsuccessfully.
+    #[error("{message}, {stdout}, {stderr}")]
+    Command {
+        message: String,
+        exit_code: Option<i32>,
+        stdout: String,
+        stderr: String,
+    },
+
+    #[error("The {step} step failed{}", exit_code.map(|code| format!(" with exit code {}", code)).unwrap_or_default())]
+    Flyctl {
+        step: String,
+        exit_code: Option<i32>,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    #[error("{0}")]
+    ConfigInvalid(String),
+
+    #[error("{0}")]
+    Secret(String),
+
+    #[error("{0}")]
+    Drift(String),
+}
+
+impl LsctlError {
+    pub fn config_parse(file: &str, error: &serde_json::Error) -> LsctlError {
+        LsctlError::config_parse_at(file, error.line(), error.column(), &error.to_string())
+    }
+
+    /// A parse error at a one-based line and column, or 0 when the position is not known.
+    pub fn config_parse_at(file: &str, line: usize, column: usize, message: &str) -> LsctlError {
+        // Parsers add the position to their messages, which would repeat it
+        let message = match message.rsplit_once(" at line ") {
+            Some((message, position))
+                if position
+                    .split(" column ")
+                    .all(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())) =>
+            {
+                message
+            }
+            _ => message,
+        };
+
+        LsctlError::ConfigParse {
+            location: ConfigLocation {
+                file: file.to_string(),
+                line,
+                column,
+                overriding: vec![],
+            },
+            message: message.to_string(),
+        }
+    }
+
+    /// The process exit code for the error. 1 is left for other errors and 2 for usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LsctlError::ConfigIo { .. } => 3,
+            LsctlError::ConfigParse { .. } | LsctlError::ConfigInvalid(_) => 4,
+            LsctlError::Command { .. } => 1,
+            LsctlError::Flyctl { .. } => 5,
+            LsctlError::Secret(_) => 6,
+            LsctlError::Drift(_) => 7,
+        }
+    }
+}
+
+/// Where in the config files an error is, e.g. `prd.json:12:5 (overriding default.json:8:3)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    /// The locations in earlier files that set the same value
+    pub overriding: Vec<ConfigLocation>,
+}
+
+impl fmt::Display for ConfigLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.file)?;
+        } else {
+            write!(f, "{}:{}:{}", self.file, self.line, self.column)?;
+        }
+
+        if !self.overriding.is_empty() {
+            let overriding = self
+                .overriding
+                .iter()
+                .rev()
+                .map(ConfigLocation::to_string)
+                .collect::<Vec<_>>();
+
+            write!(f, " (overriding {})", overriding.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The exit code of the first `LsctlError` in the error chain.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<LsctlError>())
+        .map(LsctlError::exit_code)
+        .unwrap_or(1)
+}
+
+/// The exit code of the first failed command in the error chain.
+pub fn command_exit_code(error: &anyhow::Error) -> Option<i32> {
+    error.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(LsctlError::Command { exit_code, .. }) => *exit_code,
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_where_the_config_failed_to_parse() {
+        let error = serde_json::from_str::<serde_json::Value>("{\n  \"name\": }").unwrap_err();
+
+        assert_eq!(
+            LsctlError::config_parse("prd.json", &error).to_string(),
+            "Failed to parse prd.json:2:11: expected value"
+        );
+    }
+
+    #[test]
+    fn uses_the_exit_code_of_the_outermost_error() {
+        let command = anyhow::Error::new(LsctlError::Command {
+            message: "Failed to deploy".to_string(),
+            exit_code: Some(7),
+            stdout: String::new(),
+            stderr: String::new(),
+        });
+
+        assert_eq!(command_exit_code(&command), Some(7));
+
+        let step = anyhow::Error::new(LsctlError::Flyctl {
+            step: "deploy".to_string(),
+            exit_code: command_exit_code(&command),
+            source: command,
+        })
+        .context("Deploy failed");
+
+        assert_eq!(exit_code(&step), 5);
+        assert_eq!(
+            step.chain().nth(1).unwrap().to_string(),
+            "The deploy step failed with exit code 7"
+        );
+        assert_eq!(exit_code(&anyhow::anyhow!("other")), 1);
+    }
+}
@@ -2,13 +2,20 @@ use clap::Parser;
 use commands::*;
 
 mod commands;
+mod errors;
 mod models;
 mod utils;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let command = LsctlOptions::parse().command;
+async fn main() {
+    if let Err(e) = run(LsctlOptions::parse().command).await {
+        eprintln!("Error: {:?}", e);
 
+        std::process::exit(errors::exit_code(&e));
+    }
+}
+
+async fn run(command: Command) -> anyhow::Result<()> {
     match &command {
         Command::Fly(FlySubcommand::Config(FlyConfigSubcommand::New(options))) => {
             options.execute().await
@@ -19,7 +26,26 @@ async fn main() -> anyhow::Result<()> {
         Command::Fly(FlySubcommand::Config(FlyConfigSubcommand::Schema(options))) => {
             options.execute().await
         }
+        Command::Fly(FlySubcommand::Config(FlyConfigSubcommand::Validate(options))) => {
+            options.execute().await
+        }
+        Command::Fly(FlySubcommand::Config(FlyConfigSubcommand::Explain(options))) => {
+            options.execute().await
+        }
+        Command::Fly(FlySubcommand::Config(FlyConfigSubcommand::Import(options))) => {
+            options.execute().await
+        }
         Command::Fly(FlySubcommand::Deploy(options)) => options.execute().await,
+        Command::Fly(FlySubcommand::Diff(options)) => options.execute().await,
+        Command::Fly(FlySubcommand::Rollback(options)) => options.execute().await,
+        Command::Fly(FlySubcommand::Preview(FlyPreviewSubcommand::Destroy(options))) => {
+            options.execute().await
+        }
+        Command::Fly(FlySubcommand::Preview(FlyPreviewSubcommand::List(options))) => {
+            options.execute().await
+        }
         Command::Js(JsSubcommand::Config(options)) => options.execute().await,
+        Command::Secrets(SecretsSubcommand::Encrypt(options)) => options.execute().await,
+        Command::Secrets(SecretsSubcommand::Rotate(options)) => options.execute().await,
     }
 }
@@ -1,9 +1,16 @@
-use anyhow;
-use serde_json::Value;
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use crate::{
+    errors::{ConfigLocation, LsctlError},
+    utils::config_sources::{escape_pointer_segment, ConfigSources},
+};
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
 pub struct DeployConfig {
@@ -24,6 +31,11 @@ pub struct DeployConfig {
 
     pub gcp_kms: Option<FlyGcpKms>,
     pub gcp_ssm: Option<FlyGcpSsm>,
+    pub aws_ssm: Option<FlyAwsSsm>,
+    pub aws_secrets_manager: Option<FlyAwsSecretsManager>,
+    pub vault: Option<FlyVault>,
+    pub sops: Option<FlySops>,
+    pub dotenv: Option<FlyDotenv>,
     pub database: Option<FlyDatabase>,
     pub kill_signal: Option<FlyKillSignal>,
     pub kill_timeout: Option<u64>,
@@ -32,51 +44,176 @@ pub struct DeployConfig {
     pub statics: Option<Vec<FlyStatic>>,
     pub services: Option<Vec<FlyService>>,
     pub mounts: Option<Vec<FlyMount>>,
+    pub processes: Option<HashMap<String, String>>,
+    pub metrics: Option<FlyMetrics>,
+    pub http_service: Option<FlyHttpService>,
+    pub vm: Option<Vec<FlyVm>>,
+    pub checks: Option<HashMap<String, FlyCheck>>,
+    pub console_command: Option<String>,
+    pub swap_size_mb: Option<u64>,
+    pub experimental: Option<FlyExperimental>,
     pub environment: Option<Vec<EnvironmentVariable>>,
+    /// fly.toml sections lsctl does not know, written to fly.toml as they are
+    pub extra: Option<Map<String, Value>>,
 }
 
 impl DeployConfig {
-    pub fn new(file_paths: &Vec<String>) -> anyhow::Result<DeployConfig> {
-        let json_files = file_paths
-            .iter()
-            .map(|file_path| {
-                let contents = std::fs::read_to_string(file_path)
-                    .expect(&format!("Failed to read file {}", file_path));
+    /// Deserializes the merged config files, reporting errors at the file that set the value.
+    pub fn from_sources(sources: &ConfigSources) -> anyhow::Result<DeployConfig> {
+        if sources.sources.is_empty() {
+            anyhow::bail!("No config files were given");
+        }
 
-                match serde_json::from_str(&contents) {
-                    Ok(deploy_config) => deploy_config,
-                    Err(e) => panic!("{}", e),
-                }
-            })
-            .collect::<Vec<Value>>();
+        serde_path_to_error::deserialize(sources.rendered()?).map_err(|e| {
+            let pointer = path_to_pointer(e.path());
+            let location = sources.location(&pointer).unwrap_or(ConfigLocation {
+                file: sources.file_paths().join(", "),
+                line: 0,
+                column: 0,
+                overriding: vec![],
+            });
+
+            LsctlError::ConfigParse {
+                location,
+                message: format!("{}: {}", pointer, e.inner()),
+            }
+            .into()
+        })
+    }
 
-        let mut merged = json_files[0].clone();
+    /// The environment variables that are written to the `[env]` table of fly.toml.
+    pub fn plain_environment(&self) -> Vec<EnvironmentVariable> {
+        self.environment
+            .iter()
+            .flatten()
+            .filter(|env_var| !env_var.secret)
+            .cloned()
+            .collect()
+    }
 
-        for json_file in json_files.iter().skip(1) {
-            json_patch::merge(&mut merged, json_file);
-        }
+    /// The environment variables that are pushed to the app as Fly secrets.
+    pub fn secret_environment(&self) -> Vec<EnvironmentVariable> {
+        self.environment
+            .iter()
+            .flatten()
+            .filter(|env_var| env_var.secret)
+            .cloned()
+            .collect()
+    }
 
-        match serde_json::from_value(merged) {
-            Ok(deploy_config) => Ok(deploy_config),
-            Err(e) => panic!("{}", e),
+    /// Secrets that Fly manages for the app and that must never be unset.
+    pub fn managed_secrets(&self) -> Vec<String> {
+        match self.database.as_ref().and_then(|d| d.postgres.as_ref()) {
+            Some(_) => vec!["DATABASE_URL".to_string()],
+            None => vec![],
         }
     }
 }
 
+/// Turns the path of a deserialization error into a JSON pointer.
+fn path_to_pointer(path: &serde_path_to_error::Path) -> String {
+    let mut pointer = String::new();
+
+    for segment in path.iter() {
+        let segment = match segment {
+            serde_path_to_error::Segment::Seq { index } => index.to_string(),
+            serde_path_to_error::Segment::Map { key } => escape_pointer_segment(key),
+            serde_path_to_error::Segment::Enum { variant } => escape_pointer_segment(variant),
+            serde_path_to_error::Segment::Unknown => break,
+        };
+
+        pointer.push('/');
+        pointer.push_str(&segment);
+    }
+
+    pointer
+}
+
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
 pub struct FlyHooks {
-    pub pre_deploy: Option<String>,
-    pub post_deploy: Option<String>,
+    pub pre_launch: Option<FlyHookList>,
+    pub post_launch: Option<FlyHookList>,
+    pub post_database_attach: Option<FlyHookList>,
+    pub pre_deploy: Option<FlyHookList>,
+    pub pre_scale: Option<FlyHookList>,
+    pub post_deploy: Option<FlyHookList>,
+    /// Run when a step of the deploy fails, with the step in `LSCTL_FAILED_STEP`
+    pub on_failure: Option<FlyHookList>,
+    /// Run after rolling back, with the image of the release in `LSCTL_IMAGE`
+    pub on_rollback: Option<FlyHookList>,
+}
+
+impl FlyHooks {
+    /// The hooks of a phase, e.g. `post-database-attach`.
+    pub fn phase(&self, phase: &str) -> Option<&FlyHookList> {
+        match phase {
+            "pre-launch" => self.pre_launch.as_ref(),
+            "post-launch" => self.post_launch.as_ref(),
+            "post-database-attach" => self.post_database_attach.as_ref(),
+            "pre-deploy" => self.pre_deploy.as_ref(),
+            "pre-scale" => self.pre_scale.as_ref(),
+            "post-deploy" => self.post_deploy.as_ref(),
+            "on-failure" => self.on_failure.as_ref(),
+            "on-rollback" => self.on_rollback.as_ref(),
+            _ => None,
+        }
+    }
 }
 
+/// One hook or a list of hooks that run in order.
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
-pub struct FlyAutoscaling {
-    #[serde(default = "fly_scaling_count_default")]
-    pub min_count: u64,
-    #[serde(default = "fly_scaling_count_default")]
-    pub max_count: u64,
-    #[serde(default = "FlyAutoscalingBalanceMethod::default")]
-    pub balance_method: FlyAutoscalingBalanceMethod,
+#[serde(untagged)]
+pub enum FlyHookList {
+    One(FlyHook),
+    Many(Vec<FlyHook>),
+}
+
+impl FlyHookList {
+    pub fn hooks(&self) -> Vec<FlyHookCommand> {
+        let hooks = match self {
+            FlyHookList::One(hook) => std::slice::from_ref(hook),
+            FlyHookList::Many(hooks) => hooks.as_slice(),
+        };
+
+        hooks.iter().map(FlyHook::command).collect()
+    }
+}
+
+/// A shell command, or a command with its options.
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum FlyHook {
+    Shell(String),
+    Command(FlyHookCommand),
+}
+
+impl FlyHook {
+    pub fn command(&self) -> FlyHookCommand {
+        match self {
+            FlyHook::Shell(command) => FlyHookCommand {
+                command: command.to_string(),
+                ..FlyHookCommand::default()
+            },
+            FlyHook::Command(command) => command.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyHookCommand {
+    /// Run with `sh -c` unless `args` is given
+    pub command: String,
+    /// Arguments passed to `command` as they are, without a shell
+    pub args: Option<Vec<String>>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// The working directory, defaults to the current directory
+    pub cwd: Option<String>,
+    /// Seconds the hook may run before it is stopped
+    pub timeout: Option<u64>,
+    /// Keep deploying when the hook fails
+    #[serde(default)]
+    pub continue_on_error: bool,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
@@ -93,19 +230,17 @@ impl FlyAutoscalingBalanceMethod {
     }
 
     pub fn is_static(&self) -> bool {
-        match self {
-            FlyAutoscalingBalanceMethod::Static => true,
-            _ => false,
-        }
+        matches!(self, FlyAutoscalingBalanceMethod::Static)
     }
+}
 
-    pub fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for FlyAutoscalingBalanceMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             FlyAutoscalingBalanceMethod::Balanced => "balanced",
             FlyAutoscalingBalanceMethod::Standard => "standard",
             FlyAutoscalingBalanceMethod::Static => "static",
-        }
-        .to_string()
+        })
     }
 }
 
@@ -148,27 +283,115 @@ pub struct EnvironmentVariable {
     pub key: String,
     #[serde(flatten)]
     pub value: EnvironmentVariableValue,
+    /// Push the value with `flyctl secrets import` instead of writing it to fly.toml
+    #[serde(default)]
+    pub secret: bool,
 }
 
-#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+#[derive(Clone, Deserialize, Debug, Eq, Hash, PartialEq, Serialize, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum EnvironmentVariableValue {
     Value(String),
-    FromGcpKms { value: String },
-    FromGcpSsm { name: String, version: u16 },
+    FromGcpKms {
+        value: String,
+    },
+    FromGcpSsm {
+        name: String,
+        version: u16,
+    },
+    FromAwsSsm {
+        name: String,
+    },
+    FromAwsSecretsManager {
+        secret_id: String,
+        /// Reads this key from a secret stored as a JSON object
+        key: Option<String>,
+    },
+    FromVault {
+        path: String,
+        field: String,
+    },
+    FromSopsFile {
+        file: String,
+        /// A dot separated path to the value, e.g. `database.password`
+        key: String,
+    },
+    FromDotenv {
+        file: String,
+        key: String,
+    },
+}
+
+impl EnvironmentVariableValue {
+    /// The config key the value is declared with, e.g. `from_gcp_kms`.
+    pub fn source(&self) -> &'static str {
+        match self {
+            EnvironmentVariableValue::Value(_) => "value",
+            EnvironmentVariableValue::FromGcpKms { .. } => "from_gcp_kms",
+            EnvironmentVariableValue::FromGcpSsm { .. } => "from_gcp_ssm",
+            EnvironmentVariableValue::FromAwsSsm { .. } => "from_aws_ssm",
+            EnvironmentVariableValue::FromAwsSecretsManager { .. } => "from_aws_secrets_manager",
+            EnvironmentVariableValue::FromVault { .. } => "from_vault",
+            EnvironmentVariableValue::FromSopsFile { .. } => "from_sops_file",
+            EnvironmentVariableValue::FromDotenv { .. } => "from_dotenv",
+        }
+    }
+
+    /// Whether the value comes from a secret store rather than the config itself.
+    pub fn is_secret_sourced(&self) -> bool {
+        !matches!(self, EnvironmentVariableValue::Value(_))
+    }
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
 pub struct FlyConfig {
     pub app: String,
+    pub primary_region: Option<String>,
     pub kill_signal: Option<FlyKillSignal>,
     pub kill_timeout: Option<u64>,
+    pub console_command: Option<String>,
+    pub swap_size_mb: Option<u64>,
     pub build: Option<FlyBuild>,
     pub deploy: Option<FlyDeploy>,
+    pub experimental: Option<FlyExperimental>,
+    pub processes: Option<HashMap<String, String>>,
     pub statics: Option<Vec<FlyStatic>>,
     pub services: Option<Vec<FlyService>>,
+    pub http_service: Option<FlyHttpService>,
+    pub checks: Option<HashMap<String, FlyCheck>>,
+    pub metrics: Option<FlyMetrics>,
+    pub vm: Option<Vec<FlyVm>>,
     pub mounts: Option<Vec<FlyMount>>,
     pub env: Option<HashMap<String, String>>,
+    #[serde(flatten)]
+    pub extra: Option<Map<String, Value>>,
+}
+
+impl FlyConfig {
+    /// The fly.toml for the config with the already resolved `[env]` values.
+    pub fn new(deploy_config: DeployConfig, env: HashMap<String, String>) -> FlyConfig {
+        FlyConfig {
+            app: deploy_config.name,
+            primary_region: Some(deploy_config.default_region),
+            kill_signal: deploy_config.kill_signal,
+            kill_timeout: deploy_config.kill_timeout,
+            console_command: deploy_config.console_command,
+            swap_size_mb: deploy_config.swap_size_mb,
+            build: deploy_config.build,
+            deploy: deploy_config.deploy,
+            experimental: deploy_config.experimental,
+            processes: deploy_config.processes,
+            statics: deploy_config.statics,
+            services: deploy_config.services,
+            http_service: deploy_config.http_service,
+            checks: deploy_config.checks,
+            metrics: deploy_config.metrics,
+            vm: deploy_config.vm,
+            mounts: deploy_config.mounts,
+            env: Some(env),
+            extra: deploy_config.extra,
+        }
+    }
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
@@ -208,11 +431,58 @@ pub struct FlyGcpKms {
     pub key_ring: String,
     pub key: String,
     pub location: String,
+    /// Overrides the Cloud KMS API endpoint
+    pub endpoint: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
 pub struct FlyGcpSsm {
     pub project: String,
+    /// Overrides the Secret Manager API endpoint
+    pub endpoint: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyAwsSsm {
+    pub region: Option<String>,
+    pub profile: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyAwsSecretsManager {
+    pub region: Option<String>,
+    pub profile: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyVault {
+    /// Defaults to the `VAULT_ADDR` environment variable
+    pub address: Option<String>,
+    pub namespace: Option<String>,
+    #[serde(default = "fly_vault_mount_default")]
+    pub mount: String,
+    #[serde(default = "fly_vault_kv_version_default")]
+    pub kv_version: u8,
+}
+
+fn fly_vault_mount_default() -> String {
+    "secret".to_string()
+}
+
+fn fly_vault_kv_version_default() -> u8 {
+    2
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlySops {
+    /// The sops executable, defaults to `sops`
+    pub binary: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyDotenv {
+    /// The directory `from_dotenv` files are relative to
+    pub base_dir: Option<String>,
 }
 
 #[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema, Default)]
@@ -231,7 +501,7 @@ pub struct FlyDatabasePostgres {
 }
 
 fn fly_database_postgres_cluster_size_default() -> u64 {
-    1
+    2
 }
 
 fn fly_database_postgres_volume_size_default() -> u64 {
@@ -260,16 +530,17 @@ impl FlyVmSize {
     pub fn default() -> Self {
         FlyVmSize::SharedCpu1x
     }
+}
 
-    pub fn to_string(&self) -> String {
-        match self {
+impl fmt::Display for FlyVmSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             FlyVmSize::SharedCpu1x => "shared-cpu-1x",
             FlyVmSize::DedicatedCpu1x => "dedicated-cpu-1x",
             FlyVmSize::DedicatedCpu2x => "dedicated-cpu-2x",
             FlyVmSize::DedicatedCpu4x => "dedicated-cpu-4x",
             FlyVmSize::DedicatedCpu8x => "dedicated-cpu-8x",
-        }
-        .to_string()
+        })
     }
 }
 
@@ -364,4 +635,59 @@ pub struct FlyMount {
 pub struct FlyExperimental {
     pub cmd: Option<Vec<String>>,
     pub entrypoint: Option<Vec<String>>,
+    pub auto_rollback: Option<bool>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyMetrics {
+    pub port: u64,
+    pub path: String,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyHttpService {
+    pub internal_port: u64,
+    pub force_https: Option<bool>,
+    pub auto_stop_machines: Option<bool>,
+    pub auto_start_machines: Option<bool>,
+    pub min_machines_running: Option<u64>,
+    pub processes: Option<Vec<String>>,
+    pub concurrency: Option<FlyServiceConcurrency>,
+    pub checks: Option<Vec<FlyServiceHttpCheck>>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyVm {
+    /// A VM size preset, e.g. `shared-cpu-1x`
+    pub size: Option<String>,
+    /// The memory with a unit, e.g. `1gb`
+    pub memory: Option<String>,
+    pub memory_mb: Option<u64>,
+    pub cpus: Option<u64>,
+    pub cpu_kind: Option<String>,
+    pub processes: Option<Vec<String>>,
+}
+
+/// A top-level health check, keyed by its name in `checks`.
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct FlyCheck {
+    #[serde(rename(serialize = "type", deserialize = "type"))]
+    pub the_type: FlyCheckType,
+    pub port: Option<u64>,
+    pub interval: Option<String>,
+    pub timeout: Option<String>,
+    pub grace_period: Option<String>,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub protocol: Option<FlyServiceHttpCheckProtocol>,
+    pub tls_skip_verify: Option<bool>,
+    pub headers: Option<HashMap<String, String>>,
+    pub processes: Option<Vec<String>>,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum FlyCheckType {
+    Http,
+    Tcp,
 }
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+
+/// An app as returned by `flyctl apps list --json` and `flyctl postgres list --json`.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlApp {
+    pub name: String,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub deployed: Option<bool>,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub organization: Option<FlyctlOrganization>,
+}
+
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlOrganization {
+    pub slug: String,
+}
+
+/// A secret as returned by `flyctl secrets list --json`. Only the name and digest are exposed.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlSecret {
+    pub name: String,
+    #[serde(default)]
+    pub digest: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+}
+
+/// The regions of an app as returned by `flyctl regions list --json`.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlRegions {
+    #[serde(default)]
+    pub regions: Vec<FlyctlRegion>,
+    #[serde(default)]
+    pub backup_regions: Vec<FlyctlRegion>,
+}
+
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlRegion {
+    pub code: String,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// The VM size of an app as returned by `flyctl scale show --json`.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlVmSize {
+    pub name: String,
+    #[serde(rename = "MemoryMB", default)]
+    pub memory_mb: u64,
+}
+
+/// The autoscaling config of an app as returned by `flyctl autoscale show --json`.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlAutoscale {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub min_count: u64,
+    #[serde(default)]
+    pub max_count: u64,
+    #[serde(default)]
+    pub balance_regions: bool,
+}
+
+/// The status of an app as returned by `flyctl status --json`.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlAppStatus {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub allocations: Vec<FlyctlAllocation>,
+}
+
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlAllocation {
+    #[serde(rename = "ID", default)]
+    pub id: String,
+    #[serde(default)]
+    pub region: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub version: Option<u64>,
+}
+
+/// A release as returned by `flyctl releases --image --json`.
+#[derive(Clone, Deserialize, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct FlyctlRelease {
+    pub version: u64,
+    #[serde(default)]
+    pub stable: bool,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub image_ref: Option<String>,
+    #[serde(default)]
+    pub created_at: Option<String>,
+}
@@ -1 +1,2 @@
 pub mod fly_models;
+pub mod flyctl_models;
@@ -0,0 +1,97 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use super::command_utils;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetParameterOutput {
+    parameter: Parameter,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Parameter {
+    value: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetSecretValueOutput {
+    secret_string: Option<String>,
+}
+
+fn aws(region: Option<&str>, profile: Option<&str>) -> Command {
+    let mut command = Command::new("aws");
+    command.arg("--output").arg("json");
+
+    if let Some(region) = region {
+        command.arg("--region").arg(region);
+    }
+
+    if let Some(profile) = profile {
+        command.arg("--profile").arg(profile);
+    }
+
+    command
+}
+
+pub async fn get_parameter(
+    region: Option<&str>,
+    profile: Option<&str>,
+    name: &str,
+) -> Result<String> {
+    let stdout = command_utils::async_stdout_or_bail(
+        aws(region, profile)
+            .arg("ssm")
+            .arg("get-parameter")
+            .arg("--with-decryption")
+            .arg("--name")
+            .arg(name),
+        "Failed to get SSM parameter",
+    )
+    .await?;
+
+    let output: GetParameterOutput = serde_json::from_str(&stdout)?;
+
+    Ok(output.parameter.value)
+}
+
+pub async fn get_secret_value(
+    region: Option<&str>,
+    profile: Option<&str>,
+    secret_id: &str,
+    key: Option<&str>,
+) -> Result<String> {
+    let stdout = command_utils::async_stdout_or_bail(
+        aws(region, profile)
+            .arg("secretsmanager")
+            .arg("get-secret-value")
+            .arg("--secret-id")
+            .arg(secret_id),
+        "Failed to get secret value",
+    )
+    .await?;
+
+    let output: GetSecretValueOutput = serde_json::from_str(&stdout)?;
+    let secret_string = match output.secret_string {
+        Some(secret_string) => secret_string,
+        None => bail!("Secret {} does not have a string value", secret_id),
+    };
+
+    match key {
+        Some(key) => {
+            let secret: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&secret_string)
+                    .with_context(|| format!("Secret {} is not a JSON object", secret_id))?;
+
+            match secret.get(key) {
+                Some(serde_json::Value::String(value)) => Ok(value.to_string()),
+                Some(value) => Ok(value.to_string()),
+                None => bail!("Secret {} does not have key {}", secret_id, key),
+            }
+        }
+        None => Ok(secret_string),
+    }
+}
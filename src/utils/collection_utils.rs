@@ -1,9 +1,13 @@
-use std::collections::HashSet;
+use std::collections::BTreeSet;
 
-pub fn join_hash_set_of_strings(hash_set: &HashSet<String>, join: &str) -> String {
-    hash_set
-        .iter()
-        .map(|s| s.as_str())
-        .collect::<Vec<_>>()
-        .join(join)
+pub fn sorted_unique<'a, I>(strings: I) -> Vec<String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    strings
+        .into_iter()
+        .cloned()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
 }
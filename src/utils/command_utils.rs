@@ -1,18 +1,23 @@
 use std::{
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, Read, Write},
     process::{Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+
+use crate::errors::LsctlError;
 
 pub fn stdout_or_bail(output: Output, failure_message: &str) -> Result<String> {
     if !output.status.success() {
-        bail!(
-            "{}, {}, {}",
-            failure_message,
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
+        return Err(LsctlError::Command {
+            message: failure_message.to_string(),
+            exit_code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        }
+        .into());
     }
 
     match String::from_utf8(output.stdout) {
@@ -27,19 +32,37 @@ pub fn stdout_or_bail2(command: &mut Command, failure_message: &str) -> Result<S
         .stderr(Stdio::piped())
         .output()?;
 
-    if !output.status.success() {
-        bail!(
-            "{}, {}, {}",
-            failure_message,
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
+    stdout_or_bail(output, failure_message)
+}
 
-    match String::from_utf8(output.stdout) {
-        Ok(plaintext) => Ok(plaintext),
-        Err(e) => bail!(e),
-    }
+pub fn stdin_or_bail(command: &mut Command, input: &str, failure_message: &str) -> Result<String> {
+    let mut cmd = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    cmd.stdin
+        .take()
+        .context("Failed to open stdin")?
+        .write_all(input.as_bytes())?;
+
+    let output = cmd.wait_with_output()?;
+
+    stdout_or_bail(output, failure_message)
+}
+
+pub async fn async_stdout_or_bail(
+    command: &mut tokio::process::Command,
+    failure_message: &str,
+) -> Result<String> {
+    let output = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await?;
+
+    stdout_or_bail(output, failure_message)
 }
 
 pub fn stream_stdout_or_bail(command: &mut Command, failure_message: &str) -> Result<String> {
@@ -49,30 +72,90 @@ pub fn stream_stdout_or_bail(command: &mut Command, failure_message: &str) -> Re
         .spawn()?;
 
     {
-        let stdout = cmd.stdout.as_mut().unwrap();
+        let stdout = cmd.stdout.as_mut().context("Failed to open stdout")?;
         let stdout_reader = BufReader::new(stdout);
         let stdout_lines = stdout_reader.lines();
 
-        for line in stdout_lines {
-            if let Ok(line) = line {
-                println!("{}", line);
-            }
+        for line in stdout_lines.map_while(Result::ok) {
+            println!("{}", line);
         }
     }
 
     let output = cmd.wait_with_output()?;
 
-    if !output.status.success() {
-        bail!(
-            "{}, {}, {}",
-            failure_message,
-            String::from_utf8_lossy(&output.stdout),
-            String::from_utf8_lossy(&output.stderr)
-        );
-    }
+    stdout_or_bail(output, failure_message)
+}
 
-    match String::from_utf8(output.stdout) {
-        Ok(plaintext) => Ok(plaintext),
-        Err(e) => bail!(e),
-    }
+/// Like `stream_stdout_or_bail`, but kills the command when it is still running after the
+/// timeout.
+pub fn stream_stdout_with_timeout_or_bail(
+    command: &mut Command,
+    timeout: Duration,
+    failure_message: &str,
+) -> Result<String> {
+    let mut cmd = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = cmd.stdout.take().context("Failed to open stdout")?;
+    let mut stderr = cmd.stderr.take().context("Failed to open stderr")?;
+
+    let stdout_reader = thread::spawn(move || {
+        let mut plaintext = String::new();
+
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            println!("{}", line);
+            plaintext.push_str(&line);
+            plaintext.push('\n');
+        }
+
+        plaintext
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut plaintext = vec![];
+        let _ = stderr.read_to_end(&mut plaintext);
+
+        plaintext
+    });
+
+    let deadline = Instant::now() + timeout;
+
+    let status = loop {
+        if let Some(status) = cmd.try_wait()? {
+            break status;
+        }
+
+        if Instant::now() >= deadline {
+            cmd.kill()?;
+            cmd.wait()?;
+
+            // The readers are left behind as children of the command may still hold the pipes
+            return Err(LsctlError::Command {
+                message: format!(
+                    "{} because it did not finish within {} seconds",
+                    failure_message,
+                    timeout.as_secs()
+                ),
+                exit_code: None,
+                stdout: String::new(),
+                stderr: String::new(),
+            }
+            .into());
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    let stdout = stdout_reader.join().unwrap_or_default();
+    let stderr = stderr_reader.join().unwrap_or_default();
+
+    stdout_or_bail(
+        Output {
+            status,
+            stdout: stdout.into_bytes(),
+            stderr,
+        },
+        failure_message,
+    )
 }
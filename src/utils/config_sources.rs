@@ -0,0 +1,930 @@
+use std::{
+    collections::HashMap,
+    fs,
+    iter::Peekable,
+    path::{Path, PathBuf},
+    str::Chars,
+};
+
+use serde_json::Value;
+use yaml_rust::{
+    parser::{Event, MarkedEventReceiver, Parser},
+    scanner::Marker,
+};
+
+use super::config_template;
+use crate::errors::{ConfigLocation, LsctlError};
+
+/// The formats config files can be written in, detected by the file extension.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Json5,
+    Yaml,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// The extensions that are tried, in order, when looking for a config file by name.
+    pub const EXTENSIONS: &'static [&'static str] = &["json", "json5", "yaml", "yml", "toml"];
+
+    /// The format of the file, JSON when the extension is not known.
+    pub fn of(file: &str) -> ConfigFormat {
+        match Path::new(file).extension().and_then(|e| e.to_str()) {
+            Some("json5") => ConfigFormat::Json5,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    pub fn parse(&self, file: &str, contents: &str) -> Result<Value, LsctlError> {
+        match self {
+            ConfigFormat::Json => {
+                serde_json::from_str(contents).map_err(|e| LsctlError::config_parse(file, &e))
+            }
+            ConfigFormat::Json5 => json5::from_str(contents).map_err(|e| match e {
+                json5::Error::Message { msg, location } => {
+                    let (line, column) = location
+                        .map(|location| (location.line, location.column))
+                        .unwrap_or_default();
+
+                    LsctlError::config_parse_at(file, line, column, &msg)
+                }
+            }),
+            ConfigFormat::Yaml => serde_yaml::from_str(contents).map_err(|e| {
+                let (line, column) = e
+                    .location()
+                    .map(|location| (location.line(), location.column()))
+                    .unwrap_or_default();
+
+                LsctlError::config_parse_at(file, line, column, &e.to_string())
+            }),
+            ConfigFormat::Toml => toml::from_str(contents).map_err(|e| {
+                let (line, column) = e
+                    .line_col()
+                    .map(|(line, column)| (line + 1, column + 1))
+                    .unwrap_or_default();
+
+                LsctlError::config_parse_at(file, line, column, &e.to_string())
+            }),
+        }
+    }
+
+    pub fn serialize<T: serde::Serialize>(&self, value: &T) -> anyhow::Result<String> {
+        Ok(match self {
+            ConfigFormat::Json | ConfigFormat::Json5 => serde_json::to_string_pretty(value)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+            ConfigFormat::Toml => toml::Value::try_from(value)?.to_string(),
+        })
+    }
+}
+
+/// One parsed input config file and where each of its values is in the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigSource {
+    pub file: String,
+    pub json: Value,
+    /// The line and column of every value, keyed by JSON pointer
+    positions: HashMap<String, (usize, usize)>,
+}
+
+impl ConfigSource {
+    /// Parses the file in the format of its extension and indexes the position of its values.
+    /// Values that can't be located, like the elements of TOML arrays, are reported at the file.
+    pub fn parse(file: &str, contents: &str) -> Result<ConfigSource, LsctlError> {
+        let format = ConfigFormat::of(file);
+
+        Ok(ConfigSource {
+            file: file.to_string(),
+            json: format.parse(file, contents)?,
+            positions: match format {
+                ConfigFormat::Json | ConfigFormat::Json5 => index_positions(contents),
+                ConfigFormat::Yaml => index_yaml_positions(contents),
+                ConfigFormat::Toml => index_toml_positions(contents),
+            },
+        })
+    }
+
+    /// Removes the `extends` key and returns the files it names.
+    fn take_extends(&mut self) -> Result<Vec<String>, LsctlError> {
+        let extends = match self.json.as_object_mut().and_then(|o| o.remove("extends")) {
+            Some(extends) => extends,
+            None => return Ok(vec![]),
+        };
+
+        match extends {
+            Value::String(parent) => Ok(vec![parent]),
+            Value::Array(parents) => parents
+                .into_iter()
+                .map(|parent| match parent {
+                    Value::String(parent) => Ok(parent),
+                    _ => Err(self.extends_error()),
+                })
+                .collect(),
+            _ => Err(self.extends_error()),
+        }
+    }
+
+    fn extends_error(&self) -> LsctlError {
+        LsctlError::ConfigInvalid(format!(
+            "{} extends must be a file name or a list of file names",
+            self.location("/extends")
+        ))
+    }
+
+    fn location(&self, pointer: &str) -> ConfigLocation {
+        let (line, column) = self.positions.get(pointer).copied().unwrap_or_default();
+
+        ConfigLocation {
+            file: self.file.to_string(),
+            line,
+            column,
+            overriding: vec![],
+        }
+    }
+}
+
+/// The input config files in the order they are merged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigSources {
+    pub sources: Vec<ConfigSource>,
+}
+
+impl ConfigSources {
+    /// Reads the files in order. The files a config `extends` are read before it and each file is
+    /// only read once.
+    pub fn read(file_paths: &[String]) -> Result<ConfigSources, LsctlError> {
+        let mut sources = ConfigSources { sources: vec![] };
+        let mut loaded = vec![];
+
+        for file_path in file_paths {
+            sources.load(Path::new(file_path), &mut vec![], &mut loaded)?;
+        }
+
+        Ok(sources)
+    }
+
+    /// Reads the `default` config, when there is one, and the `<env>` config from the config
+    /// directory, in any of the supported formats.
+    pub fn read_env(config_dir: &str, env: &str) -> Result<ConfigSources, LsctlError> {
+        let config_dir = Path::new(config_dir);
+        let mut file_paths = vec![];
+
+        if env != "default" {
+            if let Some(default) = find_config_file(config_dir, "default") {
+                file_paths.push(default.display().to_string());
+            }
+        }
+
+        let env_file = find_config_file(config_dir, env)
+            .unwrap_or_else(|| config_dir.join(format!("{}.json", env)));
+        file_paths.push(env_file.display().to_string());
+
+        ConfigSources::read(&file_paths)
+    }
+
+    fn load(
+        &mut self,
+        file_path: &Path,
+        extending: &mut Vec<PathBuf>,
+        loaded: &mut Vec<PathBuf>,
+    ) -> Result<(), LsctlError> {
+        let file = file_path.display().to_string();
+        let contents = fs::read_to_string(file_path).map_err(|e| LsctlError::ConfigIo {
+            file: file.to_string(),
+            source: e,
+        })?;
+        let canonical = fs::canonicalize(file_path).unwrap_or_else(|_| file_path.to_path_buf());
+
+        if extending.contains(&canonical) {
+            let cycle = extending
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>();
+
+            return Err(LsctlError::ConfigInvalid(format!(
+                "Config files extend each other in a cycle: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        if loaded.contains(&canonical) {
+            return Ok(());
+        }
+
+        let mut source = ConfigSource::parse(&file, &contents)?;
+        let parent_dir = file_path.parent().unwrap_or_else(|| Path::new(""));
+
+        extending.push(canonical.clone());
+
+        for parent in source.take_extends()? {
+            self.load(&parent_dir.join(parent), extending, loaded)?;
+        }
+
+        extending.pop();
+        loaded.push(canonical);
+        self.sources.push(source);
+
+        Ok(())
+    }
+
+    pub fn file_paths(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .map(|source| source.file.to_string())
+            .collect()
+    }
+
+    /// Adds `--set` overrides like `scaling.max_count=2`, which are merged after every file.
+    pub fn set(&mut self, overrides: &[String]) -> anyhow::Result<()> {
+        if !overrides.is_empty() {
+            self.sources.push(ConfigSource {
+                file: "--set".to_string(),
+                json: config_template::overrides_to_json(overrides)?,
+                positions: HashMap::new(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// The merged config with its templates rendered.
+    pub fn rendered(&self) -> anyhow::Result<Value> {
+        config_template::render(&self.merged())
+    }
+
+    /// Merges the files in order with JSON merge patch.
+    pub fn merged(&self) -> Value {
+        let mut sources = self.sources.iter();
+        let mut merged = match sources.next() {
+            Some(source) => source.json.clone(),
+            None => Value::Null,
+        };
+
+        for source in sources {
+            json_patch::merge(&mut merged, &source.json);
+        }
+
+        merged
+    }
+
+    /// Every file that sets the value at the pointer with its location, in merge order.
+    pub fn chain(&self, pointer: &str) -> Vec<(ConfigLocation, &Value)> {
+        self.sources
+            .iter()
+            .filter_map(|source| {
+                source
+                    .json
+                    .pointer(pointer)
+                    .map(|value| (source.location(pointer), value))
+            })
+            .collect()
+    }
+
+    /// Where the value at the pointer was set, or the closest parent that was set, along with the
+    /// locations it overrode.
+    pub fn location(&self, pointer: &str) -> Option<ConfigLocation> {
+        let mut pointer = pointer.to_string();
+
+        loop {
+            let mut chain = self
+                .chain(&pointer)
+                .into_iter()
+                .map(|(location, _)| location)
+                .collect::<Vec<_>>();
+
+            if let Some(mut location) = chain.pop() {
+                location.overriding = chain;
+
+                return Some(location);
+            }
+
+            match pointer.rfind('/') {
+                Some(index) => pointer.truncate(index),
+                None => return None,
+            }
+        }
+    }
+}
+
+fn find_config_file(config_dir: &Path, name: &str) -> Option<PathBuf> {
+    ConfigFormat::EXTENSIONS
+        .iter()
+        .map(|extension| config_dir.join(format!("{}.{}", name, extension)))
+        .find(|path| path.exists())
+}
+
+/// Turns a key or index into a JSON pointer segment.
+pub fn escape_pointer_segment(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+/// Finds the line and column of every value in a JSON or JSON5 document. Object members are
+/// indexed at their key. Invalid documents are indexed up to the first error.
+pub fn index_positions(contents: &str) -> HashMap<String, (usize, usize)> {
+    let mut indexer = PositionIndexer {
+        chars: contents.chars().peekable(),
+        line: 1,
+        column: 1,
+        positions: HashMap::new(),
+    };
+
+    indexer.skip_whitespace();
+    indexer.position("");
+    indexer.value("");
+
+    indexer.positions
+}
+
+struct PositionIndexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+    column: usize,
+    positions: HashMap<String, (usize, usize)>,
+}
+
+impl PositionIndexer<'_> {
+    fn next(&mut self) -> Option<char> {
+        let next = self.chars.next()?;
+
+        if next == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        Some(next)
+    }
+
+    /// Skips whitespace and JSON5 comments.
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.chars.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.next();
+                }
+                Some('/') => {
+                    let mut ahead = self.chars.clone();
+                    ahead.next();
+
+                    match ahead.next() {
+                        Some('/') => while !matches!(self.next(), Some('\n') | None) {},
+                        Some('*') => {
+                            self.next();
+                            self.next();
+
+                            while !matches!(
+                                (self.next(), self.chars.peek()),
+                                (Some('*'), Some('/')) | (None, _)
+                            ) {}
+                            self.next();
+                        }
+                        _ => return,
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn position(&mut self, pointer: &str) {
+        self.positions
+            .entry(pointer.to_string())
+            .or_insert((self.line, self.column));
+    }
+
+    fn value(&mut self, pointer: &str) -> Option<()> {
+        self.skip_whitespace();
+
+        match self.chars.peek()? {
+            '{' => self.object(pointer),
+            '[' => self.array(pointer),
+            '"' | '\'' => self.string().map(|_| ()),
+            _ => {
+                while !matches!(self.chars.peek(), Some(',' | ']' | '}' | '/') | None)
+                    && !self.chars.peek()?.is_whitespace()
+                {
+                    self.next();
+                }
+
+                Some(())
+            }
+        }
+    }
+
+    fn object(&mut self, pointer: &str) -> Option<()> {
+        self.next();
+
+        loop {
+            self.skip_whitespace();
+
+            if self.chars.peek()? == &'}' {
+                self.next();
+
+                return Some(());
+            }
+
+            let (line, column) = (self.line, self.column);
+            let key = match self.chars.peek()? {
+                '"' | '\'' => self.string()?,
+                _ => self.identifier()?,
+            };
+            let member = format!("{}/{}", pointer, escape_pointer_segment(&key));
+            self.positions
+                .entry(member.clone())
+                .or_insert((line, column));
+
+            self.skip_whitespace();
+            if self.next()? != ':' {
+                return None;
+            }
+
+            self.value(&member)?;
+            self.skip_whitespace();
+
+            match self.next()? {
+                ',' => continue,
+                '}' => return Some(()),
+                _ => return None,
+            }
+        }
+    }
+
+    fn array(&mut self, pointer: &str) -> Option<()> {
+        self.next();
+
+        for index in 0.. {
+            self.skip_whitespace();
+
+            if self.chars.peek()? == &']' {
+                self.next();
+
+                return Some(());
+            }
+
+            let element = format!("{}/{}", pointer, index);
+            self.position(&element);
+            self.value(&element)?;
+            self.skip_whitespace();
+
+            match self.next()? {
+                ',' => continue,
+                ']' => return Some(()),
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    /// Reads a double or single quoted string, turning it into a JSON string to unescape it.
+    fn string(&mut self) -> Option<String> {
+        let quote = self.next()?;
+        let mut raw = String::from('"');
+
+        loop {
+            match self.next()? {
+                '\\' => match self.next()? {
+                    '\'' => raw.push('\''),
+                    escaped => {
+                        raw.push('\\');
+                        raw.push(escaped);
+                    }
+                },
+                '"' if quote == '\'' => raw.push_str("\\\""),
+                next if next == quote => {
+                    raw.push('"');
+
+                    return serde_json::from_str(&raw).ok();
+                }
+                next => raw.push(next),
+            }
+        }
+    }
+
+    /// Reads an unquoted JSON5 object key.
+    fn identifier(&mut self) -> Option<String> {
+        let mut key = String::new();
+
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_alphanumeric() || c == '_' || c == '$') {
+                break;
+            }
+
+            key.push(c);
+            self.next();
+        }
+
+        if key.is_empty() {
+            None
+        } else {
+            Some(key)
+        }
+    }
+}
+
+/// Finds the line and column of every value in a YAML document. Mapping values are indexed at
+/// their key. Invalid documents are indexed up to the first error.
+pub fn index_yaml_positions(contents: &str) -> HashMap<String, (usize, usize)> {
+    let mut indexer = YamlPositionIndexer {
+        frames: vec![],
+        positions: HashMap::new(),
+    };
+
+    let _ = Parser::new(contents.chars()).load(&mut indexer, false);
+
+    indexer.positions
+}
+
+enum YamlFrame {
+    Mapping {
+        pointer: String,
+        member: Option<String>,
+    },
+    Sequence {
+        pointer: String,
+        index: usize,
+    },
+}
+
+struct YamlPositionIndexer {
+    frames: Vec<YamlFrame>,
+    positions: HashMap<String, (usize, usize)>,
+}
+
+impl YamlPositionIndexer {
+    /// The pointer of the next node, which is the member it belongs to for mapping keys and
+    /// values alike.
+    fn node(&mut self, key: &str, mark: Marker) -> String {
+        let pointer = match self.frames.last_mut() {
+            None => String::new(),
+            Some(YamlFrame::Sequence { pointer, index }) => {
+                *index += 1;
+                format!("{}/{}", pointer, *index - 1)
+            }
+            Some(YamlFrame::Mapping { pointer, member }) => match member.take() {
+                Some(member) => member,
+                None => {
+                    let key = format!("{}/{}", pointer, escape_pointer_segment(key));
+                    *member = Some(key.clone());
+                    key
+                }
+            },
+        };
+
+        self.positions
+            .entry(pointer.clone())
+            .or_insert((mark.line(), mark.col() + 1));
+
+        pointer
+    }
+}
+
+impl MarkedEventReceiver for YamlPositionIndexer {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => {
+                self.node(&value, mark);
+            }
+            Event::Alias(_) => {
+                self.node("", mark);
+            }
+            Event::MappingStart(_) => {
+                let pointer = self.node("", mark);
+                self.frames.push(YamlFrame::Mapping {
+                    pointer,
+                    member: None,
+                });
+            }
+            Event::SequenceStart(_) => {
+                let pointer = self.node("", mark);
+                self.frames.push(YamlFrame::Sequence { pointer, index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.frames.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Finds the line and column of the keys and table headers in a TOML document. Values are
+/// indexed at the key or header that first names them, elements of arrays of tables at their
+/// header. Values inside inline tables and arrays are not indexed.
+pub fn index_toml_positions(contents: &str) -> HashMap<String, (usize, usize)> {
+    let mut positions = HashMap::new();
+    let mut table_arrays: HashMap<String, usize> = HashMap::new();
+    let mut table = String::new();
+    let mut multiline_string: Option<&str> = None;
+
+    positions.insert(String::new(), (1, 1));
+
+    for (number, line) in contents.lines().enumerate() {
+        if let Some(delimiter) = multiline_string {
+            if line.matches(delimiter).count() % 2 == 1 {
+                multiline_string = None;
+            }
+
+            continue;
+        }
+
+        let trimmed = line.trim_start();
+        let position = (
+            number + 1,
+            line[..line.len() - trimmed.len()].chars().count() + 1,
+        );
+
+        if let Some(header) = trimmed.strip_prefix("[[") {
+            if let Some((keys, rest)) = toml_keys(header) {
+                if !rest.starts_with("]]") {
+                    continue;
+                }
+
+                let array = toml_pointer(&table_arrays, "", &keys);
+                let index = table_arrays.entry(array.clone()).or_insert(0);
+
+                table = format!("{}/{}", array, *index);
+                *index += 1;
+                positions.entry(array).or_insert(position);
+                positions.insert(table.clone(), position);
+            }
+        } else if let Some(header) = trimmed.strip_prefix('[') {
+            if let Some((keys, rest)) = toml_keys(header) {
+                if rest.starts_with(']') {
+                    table = toml_pointer(&table_arrays, "", &keys);
+                    insert_toml_keys(&mut positions, &table_arrays, "", &keys, position);
+                }
+            }
+        } else if let Some((keys, rest)) = toml_keys(trimmed) {
+            if let Some(value) = rest.strip_prefix('=') {
+                insert_toml_keys(&mut positions, &table_arrays, &table, &keys, position);
+
+                multiline_string = ["\"\"\"", "'''"]
+                    .into_iter()
+                    .find(|delimiter| value.matches(delimiter).count() % 2 == 1);
+            }
+        }
+    }
+
+    positions
+}
+
+/// Reads dotted, bare or quoted keys and returns them with the rest of the line.
+fn toml_keys(line: &str) -> Option<(Vec<String>, &str)> {
+    let mut keys = vec![];
+    let mut rest = line;
+
+    loop {
+        rest = rest.trim_start();
+
+        let (key, after) = match rest.chars().next()? {
+            '"' => {
+                let mut escaped = false;
+                let (end, _) = rest.char_indices().skip(1).find(|&(_, c)| {
+                    let end = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    end
+                })?;
+
+                (serde_json::from_str(&rest[..=end]).ok()?, &rest[end + 1..])
+            }
+            '\'' => {
+                let end = rest[1..].find('\'')? + 1;
+                (rest[1..end].to_string(), &rest[end + 1..])
+            }
+            _ => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                    .unwrap_or(rest.len());
+
+                if end == 0 {
+                    return None;
+                }
+
+                (rest[..end].to_string(), &rest[end..])
+            }
+        };
+
+        keys.push(key);
+        rest = after.trim_start();
+
+        match rest.strip_prefix('.') {
+            Some(after) => rest = after,
+            None => return Some((keys, rest)),
+        }
+    }
+}
+
+/// The pointer of dotted keys in a table. Keys naming an array of tables refer to its last
+/// element, like they do in TOML.
+fn toml_pointer(table_arrays: &HashMap<String, usize>, table: &str, keys: &[String]) -> String {
+    let mut pointer = table.to_string();
+
+    for (index, key) in keys.iter().enumerate() {
+        pointer = format!("{}/{}", pointer, escape_pointer_segment(key));
+
+        if let Some(count) = table_arrays
+            .get(&pointer)
+            .filter(|_| index + 1 < keys.len())
+        {
+            pointer = format!("{}/{}", pointer, count - 1);
+        }
+    }
+
+    pointer
+}
+
+/// Indexes the value of dotted keys and every table they open at the position of the keys.
+fn insert_toml_keys(
+    positions: &mut HashMap<String, (usize, usize)>,
+    table_arrays: &HashMap<String, usize>,
+    table: &str,
+    keys: &[String],
+    position: (usize, usize),
+) {
+    for end in 1..=keys.len() {
+        positions
+            .entry(toml_pointer(table_arrays, table, &keys[..end]))
+            .or_insert(position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn indexes_the_position_of_every_value() {
+        let positions = index_positions(
+            r#"{
+  "name": "api",
+  "regions": ["ord", "iad"],
+  "a/b": { "c\"d": 1 }
+}"#,
+        );
+
+        assert_eq!(positions.get(""), Some(&(1, 1)));
+        assert_eq!(positions.get("/name"), Some(&(2, 3)));
+        assert_eq!(positions.get("/regions/1"), Some(&(3, 22)));
+        assert_eq!(positions.get("/a~1b/c\"d"), Some(&(4, 12)));
+    }
+
+    #[test]
+    fn locates_the_file_that_set_a_value() {
+        let sources = ConfigSources {
+            sources: vec![
+                ConfigSource::parse("default.json", "{\n  \"scaling\": { \"min_count\": 1 }\n}")
+                    .unwrap(),
+                ConfigSource::parse(
+                    "prd.json",
+                    "{\n  \"scaling\": {\n    \"min_count\": 4\n  }\n}",
+                )
+                .unwrap(),
+            ],
+        };
+
+        assert_eq!(sources.merged(), json!({ "scaling": { "min_count": 4 } }));
+        assert_eq!(
+            sources.location("/scaling/min_count").unwrap().to_string(),
+            "prd.json:3:5 (overriding default.json:2:16)"
+        );
+        assert_eq!(
+            sources.location("/scaling/max_count").unwrap().to_string(),
+            "prd.json:2:3 (overriding default.json:2:3)"
+        );
+    }
+
+    #[test]
+    fn parses_every_format_the_same() {
+        let expected =
+            json!({ "name": "api", "regions": ["ord", "iad"], "scaling": { "min_count": 1 } });
+        let files = [
+            ("fly.json", r#"{ "name": "api", "regions": ["ord", "iad"], "scaling": { "min_count": 1 } }"#),
+            (
+                "fly.json5",
+                "{\n  // The app\n  name: 'api',\n  regions: ['ord', 'iad',],\n  scaling: { min_count: 1 },\n}",
+            ),
+            ("fly.yaml", "name: api\nregions: [ord, iad]\nscaling:\n  min_count: 1\n"),
+            ("lsctl.toml", "name = \"api\"\nregions = [\"ord\", \"iad\"]\n\n[scaling]\nmin_count = 1\n"),
+        ];
+
+        for (file, contents) in files {
+            assert_eq!(
+                ConfigSource::parse(file, contents).unwrap().json,
+                expected,
+                "{}",
+                file
+            );
+        }
+
+        assert_eq!(
+            ConfigSource::parse("fly.yaml", "name: [api")
+                .unwrap_err()
+                .to_string(),
+            "Failed to parse fly.yaml:2:1: while parsing a flow sequence, expected ',' or ']'"
+        );
+    }
+
+    #[test]
+    fn locates_keys_in_every_format() {
+        let files = [
+            (
+                "fly.json5",
+                "{\n  /* The app */ name: 'api', // Or \"web\"\n  scaling: {\n    min_count: 1,\n  },\n}",
+                [(2, 17), (3, 3), (4, 5)],
+            ),
+            (
+                "fly.yaml",
+                "name: api\nscaling:\n  min_count: 1\nregions:\n  - ord\n",
+                [(1, 1), (2, 1), (3, 3)],
+            ),
+            (
+                "fly.toml",
+                "name = \"api\"\n\n[scaling]\n  min_count = 1\n",
+                [(1, 1), (3, 1), (4, 3)],
+            ),
+        ];
+
+        for (file, contents, expected) in files {
+            let source = ConfigSource::parse(file, contents).unwrap();
+            let locations = ["/name", "/scaling", "/scaling/min_count"]
+                .map(|pointer| source.positions.get(pointer).copied());
+
+            assert_eq!(locations, expected.map(Some), "{}", file);
+        }
+
+        assert_eq!(
+            index_yaml_positions("regions:\n  - ord\n  - iad\n").get("/regions/1"),
+            Some(&(3, 5))
+        );
+        assert_eq!(
+            index_toml_positions(
+                "bio = \"\"\"\nname = 1\n\"\"\"\n[[services]]\nport = 80\n\n[[services]]\n[services.http]\nport = 8080\n"
+            )
+            .into_iter()
+            .filter(|(pointer, _)| pointer.starts_with("/services"))
+            .collect::<HashMap<_, _>>(),
+            HashMap::from([
+                ("/services".to_string(), (4, 1)),
+                ("/services/0".to_string(), (4, 1)),
+                ("/services/0/port".to_string(), (5, 1)),
+                ("/services/1".to_string(), (7, 1)),
+                ("/services/1/http".to_string(), (8, 1)),
+                ("/services/1/http/port".to_string(), (9, 1)),
+            ])
+        );
+        assert_eq!(
+            ConfigSource::parse("fly.toml", "regions = [\"ord\"]\n")
+                .unwrap()
+                .location("/regions/0")
+                .to_string(),
+            "fly.toml"
+        );
+    }
+
+    #[test]
+    fn reads_the_files_a_config_extends_first() {
+        let dir = std::env::temp_dir().join(format!("lsctl-config-sources-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("base.json"), r#"{ "name": "base" }"#).unwrap();
+        fs::write(
+            dir.join("default.json"),
+            r#"{ "extends": "base.json", "organization": "personal" }"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("prd.json"),
+            r#"{ "extends": ["default.json"], "name": "prd" }"#,
+        )
+        .unwrap();
+        fs::write(dir.join("a.json"), r#"{ "extends": "b.json" }"#).unwrap();
+        fs::write(dir.join("b.json"), r#"{ "extends": "a.json" }"#).unwrap();
+
+        let sources = ConfigSources::read_env(dir.to_str().unwrap(), "prd").unwrap();
+        let cycle = ConfigSources::read(&[dir.join("a.json").display().to_string()]);
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            sources
+                .sources
+                .iter()
+                .map(|source| Path::new(&source.file)
+                    .file_name()
+                    .unwrap()
+                    .to_str()
+                    .unwrap())
+                .collect::<Vec<_>>(),
+            vec!["base.json", "default.json", "prd.json"]
+        );
+        assert_eq!(
+            sources.merged(),
+            json!({ "name": "prd", "organization": "personal" })
+        );
+        assert!(matches!(cycle, Err(LsctlError::ConfigInvalid(_))));
+    }
+}
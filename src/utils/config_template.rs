@@ -0,0 +1,244 @@
+use anyhow::{Context as _, Result};
+use handlebars::{
+    handlebars_helper, Context, Handlebars, Helper, HelperDef, RenderContext, RenderError,
+    ScopedJson,
+};
+use serde_json::{json, Map, Value};
+
+use super::config_sources::escape_pointer_segment;
+
+handlebars_helper!(join: |list: array, {sep: str = ","}| list
+    .iter()
+    .map(|value| match value {
+        Value::String(value) => value.to_string(),
+        value => value.to_string(),
+    })
+    .collect::<Vec<_>>()
+    .join(sep));
+handlebars_helper!(lower: |value: str| value.to_lowercase());
+handlebars_helper!(upper: |value: str| value.to_uppercase());
+handlebars_helper!(replace: |value: str, from: str, to: str| value.replace(from, to));
+handlebars_helper!(truncate: |value: str, length: u64| value.chars().take(length as usize).collect::<String>());
+handlebars_helper!(slug: |value: str| slugify(value));
+
+/// `{{default value fallback}}` renders the fallback when the value is missing, null or empty.
+struct DefaultHelper;
+
+impl HelperDef for DefaultHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let fallback = h
+            .param(1)
+            .ok_or_else(|| RenderError::new("`default` helper: Couldn't read parameter 1"))?;
+
+        let value = match h.param(0) {
+            Some(value) if !value.is_value_missing() => value.value(),
+            _ => fallback.value(),
+        };
+
+        Ok(ScopedJson::Derived(match value {
+            Value::Null => fallback.value().clone(),
+            Value::String(s) if s.is_empty() => fallback.value().clone(),
+            value => value.clone(),
+        }))
+    }
+}
+
+/// Lowercases the value and replaces everything that cannot be in a Fly app name with `-`.
+pub fn slugify(value: &str) -> String {
+    value
+        .to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Renders every string in the merged config as a handlebars template. Templates can use the
+/// top-level config values, e.g. `{{name}}`, and the environment variables as `{{env.NAME}}`.
+pub fn render(merged: &Value) -> Result<Value> {
+    let mut handlebars = Handlebars::new();
+    handlebars.set_strict_mode(true);
+    handlebars.register_escape_fn(handlebars::no_escape);
+    handlebars.register_helper("join", Box::new(join));
+    handlebars.register_helper("lower", Box::new(lower));
+    handlebars.register_helper("upper", Box::new(upper));
+    handlebars.register_helper("replace", Box::new(replace));
+    handlebars.register_helper("truncate", Box::new(truncate));
+    handlebars.register_helper("slug", Box::new(slug));
+    handlebars.register_helper("default", Box::new(DefaultHelper));
+
+    let env = Value::Object(
+        std::env::vars()
+            .map(|(key, value)| (key, Value::String(value)))
+            .collect(),
+    );
+
+    // Top-level strings are rendered first so other values can use them, e.g. `{{name}}`
+    let mut context = match merged {
+        Value::Object(object) => object
+            .iter()
+            .map(|(key, value)| match value {
+                Value::String(_) => Ok((
+                    key.to_string(),
+                    render_value(
+                        &handlebars,
+                        value,
+                        &json!({ "env": env }),
+                        &format!("/{}", key),
+                    )?,
+                )),
+                _ => Ok((key.to_string(), value.clone())),
+            })
+            .collect::<Result<Map<_, _>>>()?,
+        _ => Map::new(),
+    };
+    context.insert("env".to_string(), env);
+
+    render_value(&handlebars, merged, &Value::Object(context), "")
+}
+
+fn render_value(
+    handlebars: &Handlebars,
+    value: &Value,
+    context: &Value,
+    pointer: &str,
+) -> Result<Value> {
+    Ok(match value {
+        Value::String(template) if template.contains("{{") => Value::String(
+            handlebars
+                .render_template(template, context)
+                .with_context(|| format!("Failed to render the template at {}", pointer))?,
+        ),
+        Value::Array(values) => Value::Array(
+            values
+                .iter()
+                .enumerate()
+                .map(|(index, value)| {
+                    render_value(
+                        handlebars,
+                        value,
+                        context,
+                        &format!("{}/{}", pointer, index),
+                    )
+                })
+                .collect::<Result<_>>()?,
+        ),
+        Value::Object(object) => Value::Object(
+            object
+                .iter()
+                .map(|(key, value)| {
+                    let pointer = format!("{}/{}", pointer, escape_pointer_segment(key));
+
+                    Ok((
+                        key.to_string(),
+                        render_value(handlebars, value, context, &pointer)?,
+                    ))
+                })
+                .collect::<Result<_>>()?,
+        ),
+        value => value.clone(),
+    })
+}
+
+/// Turns `--set` overrides like `scaling.max_count=2` into a config that is merged last. Values
+/// are parsed as JSON when they can be and used as strings otherwise.
+pub fn overrides_to_json(overrides: &[String]) -> Result<Value> {
+    let mut config = json!({});
+
+    for set in overrides {
+        let (path, value) = set
+            .split_once('=')
+            .with_context(|| format!("{} is not in the form key=value", set))?;
+        let value = serde_json::from_str(value).unwrap_or_else(|_| json!(value));
+        let mut keys = path.split('.').peekable();
+        let mut target = &mut config;
+
+        while let Some(key) = keys.next() {
+            if key.is_empty() {
+                anyhow::bail!("{} has an empty key", set);
+            }
+
+            if !target.is_object() {
+                *target = json!({});
+            }
+
+            let object = target.as_object_mut().expect("target was made an object");
+
+            if keys.peek().is_none() {
+                object.insert(key.to_string(), value);
+
+                break;
+            }
+
+            target = object.entry(key).or_insert_with(|| json!({}));
+        }
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_templates_with_the_config_and_environment() {
+        std::env::set_var("LSCTL_TEMPLATE_TEST_SHA", "abc123");
+
+        let rendered = render(&json!({
+            "name": "{{slug \"Feature/Login Page\"}}",
+            "regions": ["ord", "iad"],
+            "build": { "image": "registry/{{name}}:{{env.LSCTL_TEMPLATE_TEST_SHA}}" },
+            "environment": [
+                { "key": "REGIONS", "value": "{{join regions sep=\" \"}}" },
+                { "key": "DATABASE", "value": "{{name}}-postgres" },
+                { "key": "LEVEL", "value": "{{default env.LSCTL_TEMPLATE_TEST_UNSET \"info\"}}" }
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            rendered,
+            json!({
+                "name": "feature-login-page",
+                "regions": ["ord", "iad"],
+                "build": { "image": "registry/feature-login-page:abc123" },
+                "environment": [
+                    { "key": "REGIONS", "value": "ord iad" },
+                    { "key": "DATABASE", "value": "feature-login-page-postgres" },
+                    { "key": "LEVEL", "value": "info" }
+                ]
+            })
+        );
+        assert_eq!(
+            render(&json!({ "extra": { "a/b~c": ["{{env.LSCTL_TEMPLATE_TEST_UNSET}}"] } }))
+                .unwrap_err()
+                .to_string(),
+            "Failed to render the template at /extra/a~1b~0c/0"
+        );
+    }
+
+    #[test]
+    fn turns_overrides_into_a_config() {
+        let overrides = vec![
+            "name=pr-12".to_string(),
+            "scaling.max_count=2".to_string(),
+            "scaling.balance_method=balanced".to_string(),
+        ];
+
+        assert_eq!(
+            overrides_to_json(&overrides).unwrap(),
+            json!({
+                "name": "pr-12",
+                "scaling": { "max_count": 2, "balance_method": "balanced" }
+            })
+        );
+        assert!(overrides_to_json(&["name".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use std::{collections::HashMap, fs};
+
+use anyhow::{Context, Result};
+
+/// Parses `KEY=value` lines, ignoring blank lines, comments and a leading `export`.
+pub fn parse(contents: &str) -> HashMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let value = value.trim();
+
+            let value = if value.len() >= 2
+                && ((value.starts_with('"') && value.ends_with('"'))
+                    || (value.starts_with('\'') && value.ends_with('\'')))
+            {
+                let unquoted = &value[1..value.len() - 1];
+
+                if value.starts_with('"') {
+                    unquoted.replace("\\n", "\n").replace("\\\"", "\"")
+                } else {
+                    unquoted.to_string()
+                }
+            } else {
+                match value.split_once(" #") {
+                    Some((value, _)) => value.trim_end().to_string(),
+                    None => value.to_string(),
+                }
+            };
+
+            Some((key.trim().to_string(), value))
+        })
+        .collect()
+}
+
+pub fn read(file_path: &str) -> Result<HashMap<String, String>> {
+    let contents = fs::read_to_string(file_path)
+        .with_context(|| format!("Failed to read dotenv file {}", file_path))?;
+
+    Ok(parse(&contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_dotenv_files() {
+        let values = parse(
+            r#"
+# database
+export DATABASE_URL=postgres://localhost/app
+PASSWORD="hunter\"2"
+SINGLE='single $quoted'
+INLINE=value # comment
+EMPTY=
+"#,
+        );
+
+        assert_eq!(values["DATABASE_URL"], "postgres://localhost/app");
+        assert_eq!(values["PASSWORD"], "hunter\"2");
+        assert_eq!(values["SINGLE"], "single $quoted");
+        assert_eq!(values["INLINE"], "value");
+        assert_eq!(values["EMPTY"], "");
+        assert_eq!(values.len(), 5);
+    }
+}
@@ -1,29 +1,18 @@
-use std::{fs, path::Path};
+use std::{fs, io, path::Path};
 
-pub fn create_dir(file_path: &str) -> Result<&str, Box<dyn std::error::Error>> {
-    let full_file_path = Path::new(file_path);
-    let prefix = full_file_path.parent().unwrap();
+pub fn create_dir(file_path: &str) -> io::Result<&str> {
+    if let Some(prefix) = Path::new(file_path).parent() {
+        fs::create_dir_all(prefix)?;
+    }
 
-    return match fs::create_dir_all(prefix) {
-        Ok(_) => Ok(full_file_path.to_str().unwrap()),
-        Err(e) => Err(Box::new(e)),
-    };
+    Ok(file_path)
 }
 
-pub fn create_and_write_file<C>(
-    file_path: &str,
-    content: C,
-) -> Result<(), Box<dyn std::error::Error>>
+pub fn create_and_write_file<C>(file_path: &str, content: C) -> io::Result<()>
 where
     C: AsRef<[u8]>,
 {
-    return match create_dir(file_path) {
-        Ok(file_path) => match fs::write(file_path, content) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(Box::new(e)),
-        },
-        Err(e) => Err(e),
-    };
+    fs::write(create_dir(file_path)?, content)
 }
 
 pub fn does_file_exist(file_path: &str) -> bool {
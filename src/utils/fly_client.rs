@@ -0,0 +1,667 @@
+use std::{collections::BTreeMap, process::Command, time::Duration};
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+
+use super::command_utils;
+use crate::models::flyctl_models::{
+    FlyctlApp, FlyctlAppStatus, FlyctlAutoscale, FlyctlRegions, FlyctlRelease, FlyctlSecret,
+    FlyctlVmSize,
+};
+
+pub static FLYCTL: &str = "flyctl";
+
+/// The flags passed through to `flyctl deploy`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlyDeployArgs {
+    pub image: Option<String>,
+    pub local_only: bool,
+    pub remote_only: bool,
+    pub no_cache: bool,
+    pub detach: bool,
+}
+
+/// An HTTP request to the public hostname of an app that Fly sends to the instances in a region.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpProbe {
+    pub url: String,
+    pub method: String,
+    pub headers: BTreeMap<String, String>,
+    pub region: String,
+    pub timeout: Duration,
+}
+
+/// The status of a probe and the region that answered it, from the `fly-region` header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpProbeResponse {
+    pub status: u16,
+    pub region: Option<String>,
+}
+
+/// The operations lsctl performs against Fly.
+pub trait FlyClient {
+    fn apps_list(&self) -> Result<Vec<FlyctlApp>>;
+    fn postgres_list(&self) -> Result<Vec<FlyctlApp>>;
+    fn secrets_list(&self, app: &str) -> Result<Vec<FlyctlSecret>>;
+    fn regions_list(&self, app: &str) -> Result<FlyctlRegions>;
+    fn scale_show(&self, app: &str) -> Result<FlyctlVmSize>;
+    fn autoscale_show(&self, app: &str) -> Result<FlyctlAutoscale>;
+    fn status(&self, app: &str) -> Result<FlyctlAppStatus>;
+    fn releases(&self, app: &str) -> Result<Vec<FlyctlRelease>>;
+    fn launch(&self, app: &str, organization: &str, region: &str) -> Result<()>;
+    fn postgres_create(
+        &self,
+        postgres_app: &str,
+        organization: &str,
+        region: &str,
+        volume_size: u64,
+        vm_size: &str,
+        cluster_size: u64,
+    ) -> Result<()>;
+    fn postgres_attach(&self, postgres_app: &str, app: &str) -> Result<()>;
+    fn secrets_import(&self, app: &str, secrets: &BTreeMap<String, String>) -> Result<()>;
+    fn secrets_unset(&self, app: &str, keys: &[String]) -> Result<()>;
+    fn deploy(&self, region: &str, args: &FlyDeployArgs) -> Result<()>;
+    fn scale_count(&self, app: &str, count: u64) -> Result<()>;
+    fn scale_memory(&self, app: &str, memory: u64) -> Result<()>;
+    fn autoscale(
+        &self,
+        app: &str,
+        balance_method: &str,
+        min_count: u64,
+        max_count: u64,
+    ) -> Result<()>;
+    fn regions_set(&self, app: &str, regions: &[String]) -> Result<()>;
+    fn regions_backup(&self, app: &str, regions: &[String]) -> Result<()>;
+    fn apps_destroy(&self, app: &str) -> Result<()>;
+    fn probe(&self, probe: &HttpProbe) -> Result<HttpProbeResponse>;
+}
+
+/// A `FlyClient` that shells out to `flyctl`.
+#[derive(Clone, Debug, Default)]
+pub struct Flyctl;
+
+impl Flyctl {
+    pub fn launch_args(app: &str, organization: &str, region: &str) -> Vec<String> {
+        to_args(&[
+            "launch",
+            "--no-deploy",
+            "--copy-config",
+            "--name",
+            app,
+            "--org",
+            organization,
+            "--region",
+            region,
+        ])
+    }
+
+    pub fn postgres_create_args(
+        postgres_app: &str,
+        organization: &str,
+        region: &str,
+        volume_size: u64,
+        vm_size: &str,
+        cluster_size: u64,
+    ) -> Vec<String> {
+        to_args(&[
+            "postgres",
+            "create",
+            "--name",
+            postgres_app,
+            "--organization",
+            organization,
+            "--region",
+            region,
+            "--volume-size",
+            &volume_size.to_string(),
+            "--initial-cluster-size",
+            &cluster_size.to_string(),
+            "--vm-size",
+            vm_size,
+        ])
+    }
+
+    pub fn postgres_attach_args(postgres_app: &str, app: &str) -> Vec<String> {
+        to_args(&[
+            "postgres",
+            "attach",
+            "--postgres-app",
+            postgres_app,
+            "--app",
+            app,
+        ])
+    }
+
+    pub fn secrets_import_args(app: &str) -> Vec<String> {
+        to_args(&["secrets", "import", "--app", app, "--stage"])
+    }
+
+    pub fn secrets_unset_args(app: &str, keys: &[String]) -> Vec<String> {
+        let mut args = to_args(&["secrets", "unset"]);
+        args.extend(keys.iter().cloned());
+        args.extend(to_args(&["--app", app, "--stage"]));
+        args
+    }
+
+    pub fn deploy_args(region: &str, args: &FlyDeployArgs) -> Vec<String> {
+        let mut deploy_args = to_args(&["deploy", "--region", region]);
+
+        if let Some(image) = &args.image {
+            deploy_args.push("--image".to_string());
+            deploy_args.push(image.to_string());
+        }
+
+        if args.local_only {
+            deploy_args.push("--local-only".to_string());
+        }
+
+        if args.remote_only {
+            deploy_args.push("--remote-only".to_string());
+        }
+
+        if args.no_cache {
+            deploy_args.push("--no-cache".to_string());
+        }
+
+        if args.detach {
+            deploy_args.push("--detach".to_string());
+        }
+
+        deploy_args
+    }
+
+    pub fn scale_count_args(app: &str, count: u64) -> Vec<String> {
+        to_args(&["scale", "count", &count.to_string(), "--app", app])
+    }
+
+    pub fn scale_memory_args(app: &str, memory: u64) -> Vec<String> {
+        to_args(&["scale", "memory", &memory.to_string(), "--app", app])
+    }
+
+    pub fn autoscale_args(
+        app: &str,
+        balance_method: &str,
+        min_count: u64,
+        max_count: u64,
+    ) -> Vec<String> {
+        to_args(&[
+            "autoscale",
+            balance_method,
+            "--app",
+            app,
+            &format!("min={}", min_count),
+            &format!("max={}", max_count),
+        ])
+    }
+
+    pub fn regions_set_args(app: &str, regions: &[String]) -> Vec<String> {
+        let mut args = to_args(&["regions", "set"]);
+        args.extend(regions.iter().cloned());
+        args.extend(to_args(&["--app", app]));
+        args
+    }
+
+    pub fn regions_backup_args(app: &str, regions: &[String]) -> Vec<String> {
+        let mut args = to_args(&["regions", "backup"]);
+        args.extend(regions.iter().cloned());
+        args.extend(to_args(&["--app", app]));
+        args
+    }
+
+    pub fn apps_destroy_args(app: &str) -> Vec<String> {
+        to_args(&["apps", "destroy", app, "--yes"])
+    }
+
+    fn query<T>(&self, args: &[&str], failure_message: &str) -> Result<T>
+    where
+        T: DeserializeOwned + Default,
+    {
+        let stdout = command_utils::stdout_or_bail2(
+            Command::new(FLYCTL).args(args).arg("--json"),
+            failure_message,
+        )?;
+
+        // flyctl prints `null` rather than `[]` when a list is empty.
+        let value: Option<T> = serde_json::from_str(&stdout)?;
+
+        Ok(value.unwrap_or_default())
+    }
+
+    fn stream(&self, args: Vec<String>, failure_message: &str) -> Result<()> {
+        command_utils::stream_stdout_or_bail(Command::new(FLYCTL).args(args), failure_message)?;
+
+        Ok(())
+    }
+}
+
+impl FlyClient for Flyctl {
+    fn apps_list(&self) -> Result<Vec<FlyctlApp>> {
+        self.query(&["apps", "list"], "Failed to get Fly apps")
+    }
+
+    fn postgres_list(&self) -> Result<Vec<FlyctlApp>> {
+        self.query(&["postgres", "list"], "Failed to get Fly Postgres clusters")
+    }
+
+    fn secrets_list(&self, app: &str) -> Result<Vec<FlyctlSecret>> {
+        self.query(
+            &["secrets", "list", "--app", app],
+            "Failed to get Fly app secrets",
+        )
+    }
+
+    fn regions_list(&self, app: &str) -> Result<FlyctlRegions> {
+        self.query(
+            &["regions", "list", "--app", app],
+            "Failed to get Fly app regions",
+        )
+    }
+
+    fn scale_show(&self, app: &str) -> Result<FlyctlVmSize> {
+        self.query(
+            &["scale", "show", "--app", app],
+            "Failed to get Fly app scaling",
+        )
+    }
+
+    fn autoscale_show(&self, app: &str) -> Result<FlyctlAutoscale> {
+        self.query(
+            &["autoscale", "show", "--app", app],
+            "Failed to get Fly app autoscaling",
+        )
+    }
+
+    fn status(&self, app: &str) -> Result<FlyctlAppStatus> {
+        self.query(&["status", "--app", app], "Failed to get Fly app status")
+    }
+
+    fn releases(&self, app: &str) -> Result<Vec<FlyctlRelease>> {
+        self.query(
+            &["releases", "--app", app, "--image"],
+            "Failed to get Fly app releases",
+        )
+    }
+
+    fn launch(&self, app: &str, organization: &str, region: &str) -> Result<()> {
+        self.stream(
+            Flyctl::launch_args(app, organization, region),
+            "Failed to start Fly app",
+        )
+    }
+
+    fn postgres_create(
+        &self,
+        postgres_app: &str,
+        organization: &str,
+        region: &str,
+        volume_size: u64,
+        vm_size: &str,
+        cluster_size: u64,
+    ) -> Result<()> {
+        self.stream(
+            Flyctl::postgres_create_args(
+                postgres_app,
+                organization,
+                region,
+                volume_size,
+                vm_size,
+                cluster_size,
+            ),
+            "Failed to create Fly app database",
+        )
+    }
+
+    fn postgres_attach(&self, postgres_app: &str, app: &str) -> Result<()> {
+        self.stream(
+            Flyctl::postgres_attach_args(postgres_app, app),
+            "Failed to attach Fly app database",
+        )
+    }
+
+    fn secrets_import(&self, app: &str, secrets: &BTreeMap<String, String>) -> Result<()> {
+        let input = secrets
+            .iter()
+            .map(|(key, value)| {
+                if value.contains('\n') {
+                    format!("{}=\"\"\"{}\"\"\"", key, value)
+                } else {
+                    format!("{}={}", key, value)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        command_utils::stdin_or_bail(
+            Command::new(FLYCTL).args(Flyctl::secrets_import_args(app)),
+            &input,
+            "Failed to import secrets to the app",
+        )?;
+
+        Ok(())
+    }
+
+    fn secrets_unset(&self, app: &str, keys: &[String]) -> Result<()> {
+        self.stream(
+            Flyctl::secrets_unset_args(app, keys),
+            "Failed to unset secrets on the app",
+        )
+    }
+
+    fn deploy(&self, region: &str, args: &FlyDeployArgs) -> Result<()> {
+        self.stream(
+            Flyctl::deploy_args(region, args),
+            "Failed to deploy the app",
+        )
+    }
+
+    fn scale_count(&self, app: &str, count: u64) -> Result<()> {
+        self.stream(
+            Flyctl::scale_count_args(app, count),
+            "Failed to set scaling on the app",
+        )
+    }
+
+    fn scale_memory(&self, app: &str, memory: u64) -> Result<()> {
+        self.stream(
+            Flyctl::scale_memory_args(app, memory),
+            "Failed to set memory on the app",
+        )
+    }
+
+    fn autoscale(
+        &self,
+        app: &str,
+        balance_method: &str,
+        min_count: u64,
+        max_count: u64,
+    ) -> Result<()> {
+        self.stream(
+            Flyctl::autoscale_args(app, balance_method, min_count, max_count),
+            "Failed to set autoscaling on the app",
+        )
+    }
+
+    fn regions_set(&self, app: &str, regions: &[String]) -> Result<()> {
+        self.stream(
+            Flyctl::regions_set_args(app, regions),
+            "Failed to set regions on the app",
+        )
+    }
+
+    fn regions_backup(&self, app: &str, regions: &[String]) -> Result<()> {
+        self.stream(
+            Flyctl::regions_backup_args(app, regions),
+            "Failed to set backup regions on the app",
+        )
+    }
+
+    fn apps_destroy(&self, app: &str) -> Result<()> {
+        self.stream(Flyctl::apps_destroy_args(app), "Failed to destroy the app")
+    }
+
+    fn probe(&self, probe: &HttpProbe) -> Result<HttpProbeResponse> {
+        let probe = probe.clone();
+
+        // Deploy steps run synchronously inside the runtime of the command, where the blocking
+        // client can't be used, so the request is sent from a thread of its own
+        std::thread::spawn(move || {
+            let method = reqwest::Method::from_bytes(probe.method.as_bytes())?;
+            let mut request = reqwest::blocking::Client::new()
+                .request(method, &probe.url)
+                .timeout(probe.timeout)
+                .header("fly-prefer-region", &probe.region);
+
+            for (name, value) in &probe.headers {
+                request = request.header(name, value);
+            }
+
+            let response = request.send()?;
+
+            Ok(HttpProbeResponse {
+                status: response.status().as_u16(),
+                region: response
+                    .headers()
+                    .get("fly-region")
+                    .and_then(|region| region.to_str().ok())
+                    .map(String::from),
+            })
+        })
+        .join()
+        .map_err(|_| anyhow::anyhow!("Failed to send the health check"))?
+    }
+}
+
+fn to_args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
+
+/// A `FlyClient` that records every call instead of talking to Fly.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordingFlyClient {
+    pub apps: Vec<FlyctlApp>,
+    pub postgres: Vec<FlyctlApp>,
+    pub secrets: Vec<FlyctlSecret>,
+    pub regions: FlyctlRegions,
+    pub vm_size: FlyctlVmSize,
+    pub autoscale: FlyctlAutoscale,
+    pub status: FlyctlAppStatus,
+    pub releases: Vec<FlyctlRelease>,
+    /// Probes of these regions answer with 503
+    pub unhealthy_regions: Vec<String>,
+    /// The `fly-region` every probe is answered with instead of the probed region, `Some(None)`
+    /// to answer without the header
+    pub answering_region: Option<Option<String>>,
+    pub fail_on: Option<String>,
+    pub calls: std::cell::RefCell<Vec<String>>,
+}
+
+#[cfg(test)]
+impl RecordingFlyClient {
+    pub fn calls(&self) -> Vec<String> {
+        self.calls.borrow().clone()
+    }
+
+    fn record(&self, call: String) -> Result<()> {
+        let failed = matches!(&self.fail_on, Some(fail_on) if call.starts_with(fail_on.as_str()));
+
+        self.calls.borrow_mut().push(call.clone());
+
+        if failed {
+            anyhow::bail!("{} failed", call);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl FlyClient for RecordingFlyClient {
+    fn apps_list(&self) -> Result<Vec<FlyctlApp>> {
+        self.record("apps list".to_string())?;
+
+        Ok(self.apps.clone())
+    }
+
+    fn postgres_list(&self) -> Result<Vec<FlyctlApp>> {
+        self.record("postgres list".to_string())?;
+
+        Ok(self.postgres.clone())
+    }
+
+    fn secrets_list(&self, app: &str) -> Result<Vec<FlyctlSecret>> {
+        self.record(format!("secrets list {}", app))?;
+
+        Ok(self.secrets.clone())
+    }
+
+    fn regions_list(&self, app: &str) -> Result<FlyctlRegions> {
+        self.record(format!("regions list {}", app))?;
+
+        Ok(self.regions.clone())
+    }
+
+    fn scale_show(&self, app: &str) -> Result<FlyctlVmSize> {
+        self.record(format!("scale show {}", app))?;
+
+        Ok(self.vm_size.clone())
+    }
+
+    fn autoscale_show(&self, app: &str) -> Result<FlyctlAutoscale> {
+        self.record(format!("autoscale show {}", app))?;
+
+        Ok(self.autoscale.clone())
+    }
+
+    fn status(&self, app: &str) -> Result<FlyctlAppStatus> {
+        self.record(format!("status {}", app))?;
+
+        Ok(self.status.clone())
+    }
+
+    fn releases(&self, app: &str) -> Result<Vec<FlyctlRelease>> {
+        self.record(format!("releases {}", app))?;
+
+        Ok(self.releases.clone())
+    }
+
+    fn launch(&self, app: &str, organization: &str, region: &str) -> Result<()> {
+        self.record(format!("launch {} {} {}", app, organization, region))
+    }
+
+    fn postgres_create(
+        &self,
+        postgres_app: &str,
+        organization: &str,
+        region: &str,
+        volume_size: u64,
+        vm_size: &str,
+        cluster_size: u64,
+    ) -> Result<()> {
+        self.record(format!(
+            "postgres create {} {} {} {} {} {}",
+            postgres_app, organization, region, volume_size, vm_size, cluster_size
+        ))
+    }
+
+    fn postgres_attach(&self, postgres_app: &str, app: &str) -> Result<()> {
+        self.record(format!("postgres attach {} {}", postgres_app, app))
+    }
+
+    fn secrets_import(&self, app: &str, secrets: &BTreeMap<String, String>) -> Result<()> {
+        self.record(format!(
+            "secrets import {} {}",
+            app,
+            secrets.keys().cloned().collect::<Vec<_>>().join(",")
+        ))
+    }
+
+    fn secrets_unset(&self, app: &str, keys: &[String]) -> Result<()> {
+        self.record(format!("secrets unset {} {}", app, keys.join(",")))
+    }
+
+    fn deploy(&self, region: &str, args: &FlyDeployArgs) -> Result<()> {
+        self.record(format!(
+            "deploy {} {}",
+            region,
+            args.image.as_deref().unwrap_or("-")
+        ))
+    }
+
+    fn scale_count(&self, app: &str, count: u64) -> Result<()> {
+        self.record(format!("scale count {} {}", app, count))
+    }
+
+    fn scale_memory(&self, app: &str, memory: u64) -> Result<()> {
+        self.record(format!("scale memory {} {}", app, memory))
+    }
+
+    fn autoscale(
+        &self,
+        app: &str,
+        balance_method: &str,
+        min_count: u64,
+        max_count: u64,
+    ) -> Result<()> {
+        self.record(format!(
+            "autoscale {} {} {} {}",
+            app, balance_method, min_count, max_count
+        ))
+    }
+
+    fn regions_set(&self, app: &str, regions: &[String]) -> Result<()> {
+        self.record(format!("regions set {} {}", app, regions.join(",")))
+    }
+
+    fn regions_backup(&self, app: &str, regions: &[String]) -> Result<()> {
+        self.record(format!("regions backup {} {}", app, regions.join(",")))
+    }
+
+    fn apps_destroy(&self, app: &str) -> Result<()> {
+        self.record(format!("apps destroy {}", app))
+    }
+
+    fn probe(&self, probe: &HttpProbe) -> Result<HttpProbeResponse> {
+        self.record(format!(
+            "probe {} {} {}",
+            probe.region, probe.method, probe.url
+        ))?;
+
+        Ok(HttpProbeResponse {
+            status: if self.unhealthy_regions.contains(&probe.region) {
+                503
+            } else {
+                200
+            },
+            region: self
+                .answering_region
+                .clone()
+                .unwrap_or_else(|| Some(probe.region.to_string())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn probes_from_a_current_thread_runtime() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 16]).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 204 No Content\r\nfly-region: ord\r\nconnection: close\r\n\r\n",
+                )
+                .unwrap();
+        });
+
+        let response = Flyctl
+            .probe(&HttpProbe {
+                url: format!("http://{}/health", address),
+                method: "GET".to_string(),
+                headers: BTreeMap::new(),
+                region: "ord".to_string(),
+                timeout: Duration::from_secs(5),
+            })
+            .unwrap();
+
+        assert_eq!(
+            response,
+            HttpProbeResponse {
+                status: 204,
+                region: Some("ord".to_string()),
+            }
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+static CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+static DEFAULT_TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+static METADATA_TOKEN_URI: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
+/// The credentials used to get an OAuth access token for Google Cloud APIs.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GcpCredentials {
+    /// A ready to use access token, e.g. from `GOOGLE_OAUTH_ACCESS_TOKEN`
+    AccessToken(String),
+    ServiceAccount(GcpServiceAccountKey),
+    AuthorizedUser(GcpAuthorizedUser),
+    /// The token of the default service account from the GCE metadata server
+    Metadata,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct GcpServiceAccountKey {
+    pub client_email: String,
+    pub private_key: String,
+    #[serde(default = "default_token_uri")]
+    pub token_uri: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct GcpAuthorizedUser {
+    pub client_id: String,
+    pub client_secret: String,
+    pub refresh_token: String,
+    #[serde(default = "default_token_uri")]
+    pub token_uri: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum GcpCredentialsFile {
+    ServiceAccount(GcpServiceAccountKey),
+    AuthorizedUser(GcpAuthorizedUser),
+}
+
+#[derive(Serialize)]
+struct GcpJwtClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct GcpTokenResponse {
+    access_token: String,
+}
+
+fn default_token_uri() -> String {
+    DEFAULT_TOKEN_URI.to_string()
+}
+
+impl GcpCredentials {
+    /// Finds credentials the same way the Google client libraries do: an explicit access token,
+    /// then `GOOGLE_APPLICATION_CREDENTIALS`, then the gcloud application default credentials
+    /// file, then the metadata server.
+    pub fn from_env() -> Result<GcpCredentials> {
+        if let Ok(token) = env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+            return Ok(GcpCredentials::AccessToken(token));
+        }
+
+        if let Ok(path) = env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+            return GcpCredentials::from_file(&PathBuf::from(path));
+        }
+
+        match application_default_credentials_path() {
+            Some(path) if path.exists() => GcpCredentials::from_file(&path),
+            _ => Ok(GcpCredentials::Metadata),
+        }
+    }
+
+    pub fn from_file(path: &PathBuf) -> Result<GcpCredentials> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read GCP credentials {}", path.display()))?;
+
+        match serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse GCP credentials {}", path.display()))?
+        {
+            GcpCredentialsFile::ServiceAccount(key) => Ok(GcpCredentials::ServiceAccount(key)),
+            GcpCredentialsFile::AuthorizedUser(user) => Ok(GcpCredentials::AuthorizedUser(user)),
+        }
+    }
+}
+
+fn application_default_credentials_path() -> Option<PathBuf> {
+    let config_dir = match env::var("CLOUDSDK_CONFIG") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) if cfg!(windows) => PathBuf::from(env::var("APPDATA").ok()?).join("gcloud"),
+        Err(_) => PathBuf::from(env::var("HOME").ok()?)
+            .join(".config")
+            .join("gcloud"),
+    };
+
+    Some(config_dir.join("application_default_credentials.json"))
+}
+
+/// An HTTP client for Google Cloud APIs that fetches and caches an access token.
+pub struct GcpClient {
+    pub http: reqwest::Client,
+    credentials: Option<GcpCredentials>,
+    token: Mutex<Option<String>>,
+}
+
+impl GcpClient {
+    /// Creates a client. Without credentials they are found with `GcpCredentials::from_env` on
+    /// first use.
+    pub fn new(credentials: Option<GcpCredentials>) -> GcpClient {
+        GcpClient {
+            http: reqwest::Client::new(),
+            credentials,
+            token: Mutex::new(None),
+        }
+    }
+
+    pub async fn access_token(&self) -> Result<String> {
+        let mut token = self.token.lock().await;
+
+        if let Some(token) = token.as_ref() {
+            return Ok(token.to_string());
+        }
+
+        let access_token = self.fetch_access_token().await?;
+        *token = Some(access_token.to_string());
+
+        Ok(access_token)
+    }
+
+    async fn fetch_access_token(&self) -> Result<String> {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials.clone(),
+            None => GcpCredentials::from_env()?,
+        };
+
+        let request = match &credentials {
+            GcpCredentials::AccessToken(token) => return Ok(token.to_string()),
+            GcpCredentials::ServiceAccount(key) => {
+                let iat = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let claims = GcpJwtClaims {
+                    iss: &key.client_email,
+                    scope: CLOUD_PLATFORM_SCOPE,
+                    aud: &key.token_uri,
+                    iat,
+                    exp: iat + 3600,
+                };
+                let assertion = jsonwebtoken::encode(
+                    &Header::new(Algorithm::RS256),
+                    &claims,
+                    &EncodingKey::from_rsa_pem(key.private_key.as_bytes())?,
+                )?;
+
+                self.http.post(&key.token_uri).form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", assertion.as_str()),
+                ])
+            }
+            GcpCredentials::AuthorizedUser(user) => self.http.post(&user.token_uri).form(&[
+                ("grant_type", "refresh_token"),
+                ("client_id", user.client_id.as_str()),
+                ("client_secret", user.client_secret.as_str()),
+                ("refresh_token", user.refresh_token.as_str()),
+            ]),
+            GcpCredentials::Metadata => self
+                .http
+                .get(METADATA_TOKEN_URI)
+                .header("Metadata-Flavor", "Google"),
+        };
+
+        let response = request
+            .send()
+            .await
+            .context("Failed to get a GCP access token")?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Failed to get a GCP access token, {}, {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            );
+        }
+
+        Ok(response.json::<GcpTokenResponse>().await?.access_token)
+    }
+
+    /// Sends the request with an access token and returns the JSON body of a successful response.
+    pub async fn send<T>(
+        &self,
+        request: reqwest::RequestBuilder,
+        failure_message: &str,
+    ) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+    {
+        let response = request
+            .bearer_auth(self.access_token().await?)
+            .send()
+            .await
+            .with_context(|| failure_message.to_string())?;
+
+        if !response.status().is_success() {
+            bail!(
+                "{}, {}, {}",
+                failure_message,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            );
+        }
+
+        Ok(response.json::<T>().await?)
+    }
+}
@@ -1,39 +1,155 @@
-use anyhow::Result;
-use base64;
-use std::{fs, path::Path, process::Command};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 
-use super::command_utils;
+use super::gcp_auth::GcpClient;
 
-pub fn decrypt_ciphertext(
+pub static DEFAULT_ENDPOINT: &str = "https://cloudkms.googleapis.com";
+
+#[derive(Serialize)]
+struct DecryptRequest<'a> {
+    ciphertext: &'a str,
+}
+
+#[derive(Deserialize)]
+struct DecryptResponse {
+    plaintext: String,
+}
+
+#[derive(Serialize)]
+struct EncryptRequest {
+    plaintext: String,
+}
+
+#[derive(Deserialize)]
+struct EncryptResponse {
+    ciphertext: String,
+}
+
+fn crypto_key_url(
+    endpoint: &str,
+    project_id: &str,
+    location: &str,
+    key_ring: &str,
+    key: &str,
+) -> String {
+    format!(
+        "{}/v1/projects/{}/locations/{}/keyRings/{}/cryptoKeys/{}",
+        endpoint.trim_end_matches('/'),
+        project_id,
+        location,
+        key_ring,
+        key
+    )
+}
+
+pub async fn decrypt_ciphertext(
+    client: &GcpClient,
+    endpoint: &str,
     project_id: &str,
     location: &str,
     key_ring: &str,
     key: &str,
     ciphertext: &str,
 ) -> Result<String> {
-    let ciphertext_base64 = base64::decode(ciphertext.to_string()).unwrap();
-
-    fs::write("data.kms", ciphertext_base64).unwrap();
-
-    let output = Command::new("gcloud")
-        .arg("kms")
-        .arg("decrypt")
-        .arg("--project")
-        .arg(project_id)
-        .arg("--location")
-        .arg(location)
-        .arg("--keyring")
-        .arg(key_ring)
-        .arg("--key")
-        .arg(key)
-        .arg("--plaintext-file")
-        .arg("-")
-        .arg("--ciphertext-file")
-        .arg("data.kms")
-        .output()
+    base64::decode(ciphertext).context("Ciphertext is not valid base64")?;
+
+    let url = format!(
+        "{}:decrypt",
+        crypto_key_url(endpoint, project_id, location, key_ring, key)
+    );
+
+    let response: DecryptResponse = client
+        .send(
+            client.http.post(url).json(&DecryptRequest { ciphertext }),
+            "Failed to decrypt ciphertext",
+        )
+        .await?;
+
+    Ok(String::from_utf8(base64::decode(response.plaintext)?)?)
+}
+
+/// Encrypts the plaintext with the primary version of the key and returns the base64 ciphertext.
+pub async fn encrypt_plaintext(
+    client: &GcpClient,
+    endpoint: &str,
+    project_id: &str,
+    location: &str,
+    key_ring: &str,
+    key: &str,
+    plaintext: &str,
+) -> Result<String> {
+    let url = format!(
+        "{}:encrypt",
+        crypto_key_url(endpoint, project_id, location, key_ring, key)
+    );
+
+    let response: EncryptResponse = client
+        .send(
+            client.http.post(url).json(&EncryptRequest {
+                plaintext: base64::encode(plaintext),
+            }),
+            "Failed to encrypt plaintext",
+        )
+        .await?;
+
+    Ok(response.ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{gcp_auth::GcpCredentials, test_server};
+
+    #[tokio::test]
+    async fn decrypts_with_the_kms_api() {
+        let server = test_server::serve_once(200, r#"{ "plaintext": "aHVudGVyMg==" }"#);
+        let client = GcpClient::new(Some(GcpCredentials::AccessToken("token".to_string())));
+
+        let plaintext = decrypt_ciphertext(
+            &client,
+            &server.url,
+            "project",
+            "global",
+            "ring",
+            "key",
+            "Y2lwaGVydGV4dA==",
+        )
+        .await
+        .unwrap();
+
+        let request = server.request();
+
+        assert_eq!(plaintext, "hunter2");
+        assert!(request.starts_with(
+            "POST /v1/projects/project/locations/global/keyRings/ring/cryptoKeys/key:decrypt "
+        ));
+        assert!(request.contains("authorization: Bearer token"));
+        assert!(request.ends_with(r#"{"ciphertext":"Y2lwaGVydGV4dA=="}"#));
+    }
+
+    #[tokio::test]
+    async fn encrypts_with_the_kms_api() {
+        let server = test_server::serve_once(200, r#"{ "ciphertext": "Y2lwaGVydGV4dA==" }"#);
+        let client = GcpClient::new(Some(GcpCredentials::AccessToken("token".to_string())));
+
+        let ciphertext = encrypt_plaintext(
+            &client,
+            &server.url,
+            "project",
+            "global",
+            "ring",
+            "key",
+            "hunter2",
+        )
+        .await
         .unwrap();
 
-    fs::remove_file(Path::new("data.kms")).unwrap();
+        let request = server.request();
 
-    command_utils::stdout_or_bail(output, "Failed to decrypt ciphertext")
+        assert_eq!(ciphertext, "Y2lwaGVydGV4dA==");
+        assert!(request.starts_with(
+            "POST /v1/projects/project/locations/global/keyRings/ring/cryptoKeys/key:encrypt "
+        ));
+        assert!(request.ends_with(r#"{"plaintext":"aHVudGVyMg=="}"#));
+    }
 }
@@ -1,21 +1,71 @@
-use std::process::Command;
-
 use anyhow::Result;
+use serde::Deserialize;
+
+use super::gcp_auth::GcpClient;
+
+pub static DEFAULT_ENDPOINT: &str = "https://secretmanager.googleapis.com";
+
+#[derive(Deserialize)]
+struct AccessSecretVersionResponse {
+    payload: SecretPayload,
+}
+
+#[derive(Deserialize)]
+struct SecretPayload {
+    data: String,
+}
+
+pub async fn access_secret_version(
+    client: &GcpClient,
+    endpoint: &str,
+    project_id: &str,
+    secret_name: &str,
+    version: u16,
+) -> Result<String> {
+    let url = format!(
+        "{}/v1/projects/{}/secrets/{}/versions/{}:access",
+        endpoint.trim_end_matches('/'),
+        project_id,
+        secret_name,
+        version
+    );
+
+    let response: AccessSecretVersionResponse = client
+        .send(client.http.get(url), "Failed to access secret version")
+        .await?;
+
+    Ok(String::from_utf8(base64::decode(response.payload.data)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{gcp_auth::GcpCredentials, test_server};
+
+    #[tokio::test]
+    async fn accesses_the_secret_version() {
+        let server = test_server::serve_once(200, r#"{ "payload": { "data": "aHVudGVyMg==" } }"#);
+        let client = GcpClient::new(Some(GcpCredentials::AccessToken("token".to_string())));
+
+        let secret = access_secret_version(&client, &server.url, "project", "db-password", 3)
+            .await
+            .unwrap();
+
+        assert_eq!(secret, "hunter2");
+        assert!(server
+            .request()
+            .starts_with("GET /v1/projects/project/secrets/db-password/versions/3:access "));
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_responses() {
+        let server = test_server::serve_once(404, r#"{ "error": { "code": 404 } }"#);
+        let client = GcpClient::new(Some(GcpCredentials::AccessToken("token".to_string())));
+
+        let error = access_secret_version(&client, &server.url, "project", "missing", 1)
+            .await
+            .unwrap_err();
 
-use super::command_utils;
-
-pub fn access_secret_version(project_id: &str, secret_name: &str, version: u16) -> Result<String> {
-    let output = Command::new("gcloud")
-        .arg("secrets")
-        .arg("versions")
-        .arg("access")
-        .arg(format!("{}", version))
-        .arg("--secret")
-        .arg(secret_name)
-        .arg("--project")
-        .arg(project_id)
-        .output()
-        .unwrap();
-
-    command_utils::stdout_or_bail(output, "Failed to access secret version")
+        assert!(error.to_string().contains("404"));
+    }
 }
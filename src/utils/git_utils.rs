@@ -0,0 +1,31 @@
+use std::{
+    path::Path,
+    process::{Command, Stdio},
+};
+
+/// Whether the file is tracked by git. Returns false outside of a git repository or when git
+/// is not installed.
+pub fn is_tracked(file_path: &str) -> bool {
+    let path = Path::new(file_path);
+    // Run git where the file is, so the repository of the file is used
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = match path.file_name() {
+        Some(file_name) => file_name,
+        None => return false,
+    };
+
+    Command::new("git")
+        .arg("ls-files")
+        .arg("--error-unmatch")
+        .arg("--")
+        .arg(file_name)
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
@@ -1,5 +1,18 @@
+pub mod aws_cli;
 pub mod collection_utils;
 pub mod command_utils;
+pub mod config_sources;
+pub mod config_template;
+pub mod dotenv;
 pub mod file_utils;
+pub mod fly_client;
+pub mod gcp_auth;
 pub mod gcp_kms;
 pub mod gcp_ssm;
+pub mod git_utils;
+pub mod secret_cache;
+pub mod secret_provider;
+pub mod sops;
+#[cfg(test)]
+pub mod test_server;
+pub mod vault;
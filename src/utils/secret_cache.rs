@@ -0,0 +1,235 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, OsRng},
+    AeadCore, Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Deserialize, Serialize)]
+struct CacheEntry {
+    expires_at: u64,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// An AES-GCM encrypted, per-user cache of resolved secret values. The key is read from
+/// `LSCTL_SECRET_CACHE_KEY` or generated into a file only readable by the user.
+pub struct SecretCache {
+    path: PathBuf,
+    cipher: Aes256Gcm,
+    ttl: u64,
+    entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn cache_dir() -> Result<PathBuf> {
+    if let Ok(dir) = env::var("XDG_CACHE_HOME") {
+        return Ok(PathBuf::from(dir).join("lsctl"));
+    }
+
+    if cfg!(windows) {
+        return Ok(PathBuf::from(env::var("LOCALAPPDATA")?).join("lsctl"));
+    }
+
+    Ok(PathBuf::from(env::var("HOME")?)
+        .join(".cache")
+        .join("lsctl"))
+}
+
+fn read_or_create_key(path: &Path) -> Result<Key<Aes256Gcm>> {
+    if let Ok(encoded) = env::var("LSCTL_SECRET_CACHE_KEY") {
+        return key_from_base64(&encoded);
+    }
+
+    if path.exists() {
+        return key_from_base64(fs::read_to_string(path)?.trim());
+    }
+
+    let key = Aes256Gcm::generate_key(OsRng);
+
+    create_private_file(path, base64::encode(key).as_bytes())?;
+
+    Ok(key)
+}
+
+/// Creates a file only the user can read and writes the contents through it, so they are never
+/// readable by others. Fails when the file already exists.
+fn create_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        options.mode(0o600);
+    }
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .map_err(|e| anyhow!("Error creating file {}: {}", path.display(), e))
+}
+
+fn key_from_base64(encoded: &str) -> Result<Key<Aes256Gcm>> {
+    let bytes = base64::decode(encoded).context("The secret cache key is not valid base64")?;
+
+    if bytes.len() != 32 {
+        bail!("The secret cache key must be 32 bytes");
+    }
+
+    Ok(*Key::<Aes256Gcm>::from_slice(&bytes))
+}
+
+impl SecretCache {
+    /// Opens the cache, dropping entries that have expired.
+    pub fn open(ttl: u64) -> Result<SecretCache> {
+        let dir = cache_dir().context("Unable to find the user cache directory")?;
+
+        SecretCache::open_in(dir, ttl)
+    }
+
+    pub fn open_in(dir: PathBuf, ttl: u64) -> Result<SecretCache> {
+        let key = read_or_create_key(&dir.join("secrets.key"))?;
+        let path = dir.join("secrets.json");
+
+        let entries: HashMap<String, CacheEntry> = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+        let now = now();
+
+        Ok(SecretCache {
+            path,
+            cipher: Aes256Gcm::new(&key),
+            ttl,
+            entries: Mutex::new(
+                entries
+                    .into_iter()
+                    .filter(|(_, entry)| entry.expires_at > now)
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Hashes the value reference together with the provider settings it is resolved with.
+    pub fn key(context: &str, reference: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(context.as_bytes());
+        hasher.update([0]);
+        hasher.update(reference.as_bytes());
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let entry = self.entries.lock().unwrap().get(key).cloned()?;
+
+        if entry.expires_at <= now() {
+            return None;
+        }
+
+        let nonce = base64::decode(entry.nonce).ok()?;
+        let ciphertext = base64::decode(entry.ciphertext).ok()?;
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_ref())
+            .ok()?;
+
+        String::from_utf8(plaintext).ok()
+    }
+
+    pub fn insert(&self, key: &str, value: &str) -> Result<()> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, value.as_bytes())
+            .map_err(|_| anyhow!("Failed to encrypt the cached secret"))?;
+
+        self.entries.lock().unwrap().insert(
+            key.to_string(),
+            CacheEntry {
+                expires_at: now() + self.ttl,
+                nonce: base64::encode(nonce),
+                ciphertext: base64::encode(ciphertext),
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Writes the entries to a new private file that then replaces the cache file.
+    pub fn save(&self) -> Result<()> {
+        let contents = serde_json::to_string(&*self.entries.lock().unwrap())?;
+        let new_path = self.path.with_extension("json.new");
+
+        // Left behind by a save that did not finish
+        let _ = fs::remove_file(&new_path);
+
+        create_private_file(&new_path, contents.as_bytes())?;
+        fs::rename(&new_path, &self.path)
+            .with_context(|| format!("Error replacing file {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_encrypted_values() {
+        let dir = env::temp_dir().join(format!("lsctl-secret-cache-{}", std::process::id()));
+        let key = SecretCache::key("gcp", "from_gcp_ssm api-key 1");
+
+        let cache = SecretCache::open_in(dir.clone(), 60).unwrap();
+        cache.insert(&key, "hunter2").unwrap();
+        cache.save().unwrap();
+
+        let contents = fs::read_to_string(dir.join("secrets.json")).unwrap();
+        let reopened = SecretCache::open_in(dir.clone(), 60).unwrap();
+
+        assert!(!contents.contains("hunter2"));
+        assert_eq!(reopened.get(&key), Some("hunter2".to_string()));
+        assert_eq!(reopened.get(&SecretCache::key("aws", "other")), None);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            for file in ["secrets.key", "secrets.json"] {
+                let mode = fs::metadata(dir.join(file)).unwrap().permissions().mode();
+
+                assert_eq!(mode & 0o777, 0o600, "{}", file);
+            }
+        }
+
+        reopened.save().unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+
+use super::{
+    aws_cli, dotenv, gcp_auth::GcpClient, gcp_kms, gcp_ssm, secret_cache::SecretCache, sops,
+    vault::VaultClient,
+};
+use crate::models::fly_models::*;
+
+/// Resolves the values of one kind of `EnvironmentVariableValue`.
+#[async_trait]
+pub trait SecretProvider: Send + Sync {
+    /// The config key of the values the provider resolves, e.g. `from_gcp_kms`.
+    fn name(&self) -> &'static str;
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String>;
+
+    /// Whether resolved values may be stored in the secret cache. Providers that read local
+    /// files should not be cached so edits are picked up.
+    fn cacheable(&self) -> bool {
+        true
+    }
+}
+
+/// The secret providers available to a config, looked up by name.
+pub struct SecretProviders {
+    providers: HashMap<&'static str, Box<dyn SecretProvider>>,
+    /// The provider settings, so cached values are not shared between projects or accounts
+    cache_context: String,
+}
+
+impl SecretProviders {
+    /// Registers every built-in provider with the settings from the config.
+    pub fn new(deploy_config: &DeployConfig) -> SecretProviders {
+        let gcp_client = Arc::new(GcpClient::new(None));
+        let mut providers = SecretProviders {
+            providers: HashMap::new(),
+            cache_context: serde_json::json!([
+                deploy_config.gcp_kms,
+                deploy_config.gcp_ssm,
+                deploy_config.aws_ssm,
+                deploy_config.aws_secrets_manager,
+                deploy_config.vault,
+            ])
+            .to_string(),
+        };
+
+        providers.register(Box::new(PlainValueProvider));
+        providers.register(Box::new(GcpKmsProvider {
+            client: gcp_client.clone(),
+            config: deploy_config.gcp_kms.clone(),
+        }));
+        providers.register(Box::new(GcpSsmProvider {
+            client: gcp_client,
+            config: deploy_config.gcp_ssm.clone(),
+        }));
+        providers.register(Box::new(AwsSsmProvider {
+            config: deploy_config.aws_ssm.clone(),
+        }));
+        providers.register(Box::new(AwsSecretsManagerProvider {
+            config: deploy_config.aws_secrets_manager.clone(),
+        }));
+        providers.register(Box::new(VaultProvider {
+            config: deploy_config.vault.clone(),
+        }));
+        providers.register(Box::new(SopsProvider {
+            config: deploy_config.sops.clone(),
+        }));
+        providers.register(Box::new(DotenvProvider {
+            config: deploy_config.dotenv.clone(),
+        }));
+
+        providers
+    }
+
+    pub fn register(&mut self, provider: Box<dyn SecretProvider>) {
+        self.providers.insert(provider.name(), provider);
+    }
+
+    pub async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        match self.providers.get(value.source()) {
+            Some(provider) => provider.resolve(value).await,
+            None => bail!("No secret provider is registered for {}", value.source()),
+        }
+    }
+
+    /// Resolves every distinct value once, running up to `concurrency` lookups at a time and
+    /// reading from and writing to the cache when one is given.
+    pub async fn resolve_all(
+        &self,
+        values: &[&EnvironmentVariableValue],
+        concurrency: usize,
+        cache: Option<&SecretCache>,
+    ) -> HashMap<EnvironmentVariableValue, Result<String>> {
+        let mut unique: Vec<&EnvironmentVariableValue> = vec![];
+
+        for value in values {
+            if !unique.contains(value) {
+                unique.push(value);
+            }
+        }
+
+        stream::iter(unique.into_iter().cloned())
+            .map(|value| async move {
+                let cacheable = matches!(
+                    self.providers.get(value.source()),
+                    Some(provider) if provider.cacheable()
+                );
+                let cache = cache.filter(|_| cacheable);
+                let cache_key = SecretCache::key(
+                    &self.cache_context,
+                    &serde_json::to_string(&value).unwrap_or_default(),
+                );
+
+                if let Some(cached) = cache.and_then(|cache| cache.get(&cache_key)) {
+                    return (value, Ok(cached));
+                }
+
+                let resolved = self.resolve(&value).await;
+
+                if let (Some(cache), Ok(resolved)) = (cache, &resolved) {
+                    if let Err(e) = cache.insert(&cache_key, resolved) {
+                        println!("Error caching {}: {}", value.source(), e);
+                    }
+                }
+
+                (value, resolved)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await
+    }
+}
+
+fn unexpected(provider: &dyn SecretProvider, value: &EnvironmentVariableValue) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{} cannot resolve {} values",
+        provider.name(),
+        value.source()
+    )
+}
+
+struct PlainValueProvider;
+
+#[async_trait]
+impl SecretProvider for PlainValueProvider {
+    fn name(&self) -> &'static str {
+        "value"
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        match value {
+            EnvironmentVariableValue::Value(value) => Ok(value.to_string()),
+            _ => Err(unexpected(self, value)),
+        }
+    }
+}
+
+struct GcpKmsProvider {
+    client: Arc<GcpClient>,
+    config: Option<FlyGcpKms>,
+}
+
+#[async_trait]
+impl SecretProvider for GcpKmsProvider {
+    fn name(&self) -> &'static str {
+        "from_gcp_kms"
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        let ciphertext = match value {
+            EnvironmentVariableValue::FromGcpKms { value } => value,
+            _ => return Err(unexpected(self, value)),
+        };
+        let config = self.config.as_ref().context("gcp_kms config is not set")?;
+
+        gcp_kms::decrypt_ciphertext(
+            &self.client,
+            config
+                .endpoint
+                .as_deref()
+                .unwrap_or(gcp_kms::DEFAULT_ENDPOINT),
+            &config.project,
+            &config.location,
+            &config.key_ring,
+            &config.key,
+            ciphertext,
+        )
+        .await
+    }
+}
+
+struct GcpSsmProvider {
+    client: Arc<GcpClient>,
+    config: Option<FlyGcpSsm>,
+}
+
+#[async_trait]
+impl SecretProvider for GcpSsmProvider {
+    fn name(&self) -> &'static str {
+        "from_gcp_ssm"
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        let (name, version) = match value {
+            EnvironmentVariableValue::FromGcpSsm { name, version } => (name, *version),
+            _ => return Err(unexpected(self, value)),
+        };
+        let config = self.config.as_ref().context("gcp_ssm config is not set")?;
+
+        gcp_ssm::access_secret_version(
+            &self.client,
+            config
+                .endpoint
+                .as_deref()
+                .unwrap_or(gcp_ssm::DEFAULT_ENDPOINT),
+            &config.project,
+            name,
+            version,
+        )
+        .await
+    }
+}
+
+struct AwsSsmProvider {
+    config: Option<FlyAwsSsm>,
+}
+
+#[async_trait]
+impl SecretProvider for AwsSsmProvider {
+    fn name(&self) -> &'static str {
+        "from_aws_ssm"
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        let name = match value {
+            EnvironmentVariableValue::FromAwsSsm { name } => name,
+            _ => return Err(unexpected(self, value)),
+        };
+        let config = self.config.as_ref();
+
+        aws_cli::get_parameter(
+            config.and_then(|config| config.region.as_deref()),
+            config.and_then(|config| config.profile.as_deref()),
+            name,
+        )
+        .await
+    }
+}
+
+struct AwsSecretsManagerProvider {
+    config: Option<FlyAwsSecretsManager>,
+}
+
+#[async_trait]
+impl SecretProvider for AwsSecretsManagerProvider {
+    fn name(&self) -> &'static str {
+        "from_aws_secrets_manager"
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        let (secret_id, key) = match value {
+            EnvironmentVariableValue::FromAwsSecretsManager { secret_id, key } => (secret_id, key),
+            _ => return Err(unexpected(self, value)),
+        };
+        let config = self.config.as_ref();
+
+        aws_cli::get_secret_value(
+            config.and_then(|config| config.region.as_deref()),
+            config.and_then(|config| config.profile.as_deref()),
+            secret_id,
+            key.as_deref(),
+        )
+        .await
+    }
+}
+
+struct VaultProvider {
+    config: Option<FlyVault>,
+}
+
+#[async_trait]
+impl SecretProvider for VaultProvider {
+    fn name(&self) -> &'static str {
+        "from_vault"
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        let (path, field) = match value {
+            EnvironmentVariableValue::FromVault { path, field } => (path, field),
+            _ => return Err(unexpected(self, value)),
+        };
+        let config = self.config.as_ref().context("vault config is not set")?;
+
+        VaultClient::new(config.address.as_deref(), config.namespace.as_deref())?
+            .read_field(&config.mount, config.kv_version, path, field)
+            .await
+    }
+}
+
+struct SopsProvider {
+    config: Option<FlySops>,
+}
+
+#[async_trait]
+impl SecretProvider for SopsProvider {
+    fn name(&self) -> &'static str {
+        "from_sops_file"
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        let (file, key) = match value {
+            EnvironmentVariableValue::FromSopsFile { file, key } => (file, key),
+            _ => return Err(unexpected(self, value)),
+        };
+        let binary = self
+            .config
+            .as_ref()
+            .and_then(|config| config.binary.as_deref())
+            .unwrap_or("sops");
+
+        sops::decrypt_value(binary, file, key).await
+    }
+}
+
+struct DotenvProvider {
+    config: Option<FlyDotenv>,
+}
+
+#[async_trait]
+impl SecretProvider for DotenvProvider {
+    fn name(&self) -> &'static str {
+        "from_dotenv"
+    }
+
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    async fn resolve(&self, value: &EnvironmentVariableValue) -> Result<String> {
+        let (file, key) = match value {
+            EnvironmentVariableValue::FromDotenv { file, key } => (file, key),
+            _ => return Err(unexpected(self, value)),
+        };
+        let file = match self
+            .config
+            .as_ref()
+            .and_then(|config| config.base_dir.as_ref())
+        {
+            Some(base_dir) => Path::new(base_dir).join(file).display().to_string(),
+            None => file.to_string(),
+        };
+
+        match dotenv::read(&file)?.remove(key) {
+            Some(value) => Ok(value),
+            None => bail!("{} does not have key {}", file, key),
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use anyhow::Result;
+use tokio::process::Command;
+
+use super::command_utils;
+
+/// Decrypts a single value from a sops encrypted file. `key` is a dot separated path.
+pub async fn decrypt_value(binary: &str, file: &str, key: &str) -> Result<String> {
+    let extract = key
+        .split('.')
+        .map(|part| format!("[{:?}]", part))
+        .collect::<String>();
+
+    command_utils::async_stdout_or_bail(
+        Command::new(binary)
+            .arg("--decrypt")
+            .arg("--extract")
+            .arg(extract)
+            .arg(file),
+        "Failed to decrypt sops file",
+    )
+    .await
+}
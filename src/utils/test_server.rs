@@ -0,0 +1,69 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    thread::{self, JoinHandle},
+};
+
+/// A local HTTP server that answers a single request with a canned response.
+pub struct TestServer {
+    pub url: String,
+    handle: JoinHandle<String>,
+}
+
+impl TestServer {
+    /// Waits for the request and returns it with lowercased header names.
+    pub fn request(self) -> String {
+        self.handle.join().unwrap()
+    }
+}
+
+pub fn serve_once(status: u16, body: &'static str) -> TestServer {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let handle = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        let mut content_length = 0;
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+
+            let line = match line.split_once(':') {
+                Some((name, value)) if !request.is_empty() => {
+                    format!("{}:{}", name.to_lowercase(), value)
+                }
+                _ => line,
+            };
+
+            if let Some(length) = line.strip_prefix("content-length:") {
+                content_length = length.trim().parse().unwrap();
+            }
+
+            request.push_str(&line);
+        }
+
+        let mut request_body = vec![0; content_length];
+        reader.read_exact(&mut request_body).unwrap();
+        request.push_str(&String::from_utf8(request_body).unwrap());
+
+        write!(
+            reader.get_mut(),
+            "HTTP/1.1 {} Test\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+        .unwrap();
+
+        request
+    });
+
+    TestServer { url, handle }
+}
@@ -0,0 +1,136 @@
+use std::{env, fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+#[derive(Deserialize)]
+struct ReadSecretResponse {
+    data: Map<String, Value>,
+}
+
+/// A client for reading KV secrets from Vault.
+pub struct VaultClient {
+    http: reqwest::Client,
+    address: String,
+    token: String,
+    namespace: Option<String>,
+}
+
+impl VaultClient {
+    /// Creates a client, falling back to `VAULT_ADDR` for the address and `VAULT_TOKEN` or
+    /// `~/.vault-token` for the token.
+    pub fn new(address: Option<&str>, namespace: Option<&str>) -> Result<VaultClient> {
+        let address = match address {
+            Some(address) => address.to_string(),
+            None => env::var("VAULT_ADDR").context("vault address is not set")?,
+        };
+
+        let token = match env::var("VAULT_TOKEN") {
+            Ok(token) => token,
+            Err(_) => {
+                let path = PathBuf::from(env::var("HOME").context("VAULT_TOKEN is not set")?)
+                    .join(".vault-token");
+
+                fs::read_to_string(path)
+                    .context("VAULT_TOKEN is not set")?
+                    .trim()
+                    .to_string()
+            }
+        };
+
+        Ok(VaultClient {
+            http: reqwest::Client::new(),
+            address,
+            token,
+            namespace: namespace.map(String::from),
+        })
+    }
+
+    pub async fn read_field(
+        &self,
+        mount: &str,
+        kv_version: u8,
+        path: &str,
+        field: &str,
+    ) -> Result<String> {
+        let url = match kv_version {
+            1 => format!(
+                "{}/v1/{}/{}",
+                self.address.trim_end_matches('/'),
+                mount,
+                path
+            ),
+            _ => format!(
+                "{}/v1/{}/data/{}",
+                self.address.trim_end_matches('/'),
+                mount,
+                path
+            ),
+        };
+
+        let mut request = self.http.get(url).header("X-Vault-Token", &self.token);
+
+        if let Some(namespace) = &self.namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
+
+        let response = request
+            .send()
+            .await
+            .context("Failed to read Vault secret")?;
+
+        if !response.status().is_success() {
+            bail!(
+                "Failed to read Vault secret, {}, {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            );
+        }
+
+        let mut data = response.json::<ReadSecretResponse>().await?.data;
+
+        if kv_version != 1 {
+            data = match data.remove("data") {
+                Some(Value::Object(data)) => data,
+                _ => bail!("Vault secret {} has no data", path),
+            };
+        }
+
+        match data.get(field) {
+            Some(Value::String(value)) => Ok(value.to_string()),
+            Some(value) => Ok(value.to_string()),
+            None => bail!("Vault secret {} does not have field {}", path, field),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_server;
+
+    #[tokio::test]
+    async fn reads_kv_v2_fields() {
+        let server = test_server::serve_once(
+            200,
+            r#"{ "data": { "data": { "password": "hunter2" }, "metadata": {} } }"#,
+        );
+        let client = VaultClient {
+            http: reqwest::Client::new(),
+            address: server.url.to_string(),
+            token: "token".to_string(),
+            namespace: None,
+        };
+
+        let value = client
+            .read_field("secret", 2, "api/db", "password")
+            .await
+            .unwrap();
+        let request = server.request();
+
+        assert_eq!(value, "hunter2");
+        assert!(request.starts_with("GET /v1/secret/data/api/db "));
+        assert!(request.contains("x-vault-token: token"));
+    }
+}